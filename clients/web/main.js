@@ -1,26 +1,18 @@
 angular.module('app', []).constant('_', window._).controller('coreController', function ($scope) {
     $scope.results = [];
 
-    function reload() {
-        $.get({ url: 'http://localhost:8080' }).done(rawResult => {
-            $scope.$apply(() => {
-                $scope.results = {
-                    connections: rawResult.connections.sort((a, b) => (b.bytes_downloaded + b.bytes_uploaded) - (a.bytes_downloaded + a.bytes_uploaded)),
-                    processes: rawResult.processes
-                };
-            });
-            console.log({ results: $scope.results });
-        })
-    }
+    const events = new EventSource('http://localhost:8080/events?sort=total&order=desc&interval=1000');
 
-    function timer() {
-        setTimeout(() => {
-            reload();
-            timer();
-        }, 1000);
-    }
-
-    timer();
+    events.addEventListener('snapshot', event => {
+        const rawResult = JSON.parse(event.data);
+        $scope.$apply(() => {
+            $scope.results = {
+                connections: rawResult.connections,
+                processes: rawResult.processes
+            };
+        });
+        console.log({ results: $scope.results });
+    });
 
     $scope.findProcess = (pid) => {
         const process = $scope.results.processes[`${pid}`];
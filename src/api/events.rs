@@ -0,0 +1,69 @@
+use std::convert::Infallible;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use actix_web::{get, rt, web, HttpResponse};
+use actix_web::http::header;
+use actix_web::web::Bytes;
+use futures_util::stream;
+use serde_derive::Deserialize;
+use utoipa::IntoParams;
+
+use crate::api::snapshot;
+use crate::structs::connection::ConnectionSort;
+use crate::structs::state::State;
+
+const DEFAULT_INTERVAL: u64 = 1000;
+const MINIMUM_INTERVAL: u64 = 100;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct EventsQuery {
+    /// Milliseconds between pushes, at least 100
+    pub interval: Option<u64>,
+}
+
+impl EventsQuery {
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval.unwrap_or(DEFAULT_INTERVAL).max(MINIMUM_INTERVAL))
+    }
+}
+
+// Server-Sent Events stream of the same snapshot `GET /` returns, refreshed from the receivers on every tick
+#[utoipa::path(
+    params(ConnectionSort, EventsQuery),
+    responses((status = 200, description = "`snapshot` events carrying the body of `GET /`", content_type = "text/event-stream", body = String)),
+)]
+#[get("/events")]
+pub async fn events(state: web::Data<Mutex<State>>, sort: web::Query<ConnectionSort>, query: web::Query<EventsQuery>) -> HttpResponse {
+    let ticks = rt::time::interval(query.interval());
+    let sort = sort.into_inner();
+
+    let snapshots = stream::unfold((state, ticks), move |(state, mut ticks)| async move {
+        ticks.tick().await;
+
+        let payload = serde_json::to_string(&snapshot(&mut state.lock().unwrap(), sort, None)).unwrap_or_default();
+        let event = Bytes::from(format!("event: snapshot\ndata: {}\n\n", payload));
+
+        Some((Ok::<_, Infallible>(event), (state, ticks)))
+    });
+
+    HttpResponse::Ok()
+        .insert_header((header::CONTENT_TYPE, "text/event-stream"))
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .streaming(snapshots)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::EventsQuery;
+
+    #[test]
+    fn clamps_interval() {
+        assert_eq!(EventsQuery::default().interval(), Duration::from_millis(1000));
+        assert_eq!(EventsQuery { interval: Some(10) }.interval(), Duration::from_millis(100));
+        assert_eq!(EventsQuery { interval: Some(250) }.interval(), Duration::from_millis(250));
+    }
+}
@@ -0,0 +1,262 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::Ordering;
+
+use actix_web::{get, web, HttpResponse};
+
+use crate::structs::aggregate::ProcessTraffic;
+use crate::structs::connection::{Connections, TransportType};
+use crate::structs::health::{CaptureHealth, ScanHealth};
+use crate::structs::state::State;
+
+// Prometheus text exposition format
+#[derive(Default)]
+struct MetricsWriter {
+    output: String,
+}
+
+impl MetricsWriter {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.output, "# HELP {} {}", name, help);
+        let _ = writeln!(self.output, "# TYPE {} {}", name, kind);
+    }
+
+    fn sample<V: std::fmt::Display>(&mut self, name: &str, labels: &[(&str, &str)], value: V) {
+        self.output.push_str(name);
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels.iter()
+                .map(|(label, value)| format!("{}=\"{}\"", label, escape_label(value)))
+                .collect();
+            let _ = write!(self.output, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.output, " {}", value);
+    }
+
+    fn traffic(&mut self, name: &str, labels: &[(&str, &str)], uploaded: usize, downloaded: usize) {
+        let mut upload_labels = labels.to_vec();
+        upload_labels.push(("direction", "upload"));
+        self.sample(name, &upload_labels, uploaded);
+
+        let mut download_labels = labels.to_vec();
+        download_labels.push(("direction", "download"));
+        self.sample(name, &download_labels, downloaded);
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn transport_label(transport_type: &TransportType) -> &'static str {
+    match transport_type {
+        TransportType::Tcp => "tcp",
+        TransportType::Udp => "udp",
+    }
+}
+
+pub fn render(connections: &Connections, process_traffic: &ProcessTraffic, health: &CaptureHealth, scans: &ScanHealth) -> String {
+    let mut tracked: BTreeMap<&'static str, usize> = BTreeMap::new();
+    let mut active: BTreeMap<&'static str, usize> = BTreeMap::new();
+
+    for transport_type in [TransportType::Tcp, TransportType::Udp].iter() {
+        tracked.insert(transport_label(transport_type), 0);
+        active.insert(transport_label(transport_type), 0);
+    }
+
+    for connection in connections {
+        let transport = transport_label(&connection.transport_type);
+        *tracked.entry(transport).or_default() += 1;
+        if connection.rates.iter().any(|rate| rate.upload > 0.0 || rate.download > 0.0) {
+            *active.entry(transport).or_default() += 1;
+        }
+    }
+
+    let mut writer = MetricsWriter::default();
+
+    // The start time tells apart processes that had the same pid, including exited ones
+    writer.family("crystalline_process_bytes_total", "counter", "Bytes transferred by a process");
+    for (key, (executable, traffic)) in &process_traffic.totals {
+        let (pid, start_time) = (key.pid.to_string(), key.start_time.to_string());
+        writer.traffic("crystalline_process_bytes_total", &[("pid", &pid), ("start_time", &start_time), ("executable", executable)], traffic.bytes_uploaded, traffic.bytes_downloaded);
+    }
+
+    writer.family("crystalline_process_packets_total", "counter", "Packets transferred by a process");
+    for (key, (executable, traffic)) in &process_traffic.totals {
+        let (pid, start_time) = (key.pid.to_string(), key.start_time.to_string());
+        writer.traffic("crystalline_process_packets_total", &[("pid", &pid), ("start_time", &start_time), ("executable", executable)], traffic.packets_uploaded, traffic.packets_downloaded);
+    }
+
+    let remotes = health.remotes.lock().unwrap();
+    writer.family("crystalline_remote_bytes_total", "counter", "Bytes transferred with a remote address");
+    for (remote, traffic) in remotes.iter() {
+        writer.traffic("crystalline_remote_bytes_total", &[("remote", &remote.to_string())], traffic.bytes_uploaded, traffic.bytes_downloaded);
+    }
+
+    writer.family("crystalline_remote_packets_total", "counter", "Packets transferred with a remote address");
+    for (remote, traffic) in remotes.iter() {
+        writer.traffic("crystalline_remote_packets_total", &[("remote", &remote.to_string())], traffic.packets_uploaded, traffic.packets_downloaded);
+    }
+    drop(remotes);
+
+    writer.family("crystalline_connections", "gauge", "Connections currently tracked");
+    for (transport, count) in &tracked {
+        writer.sample("crystalline_connections", &[("transport", transport)], count);
+    }
+
+    writer.family("crystalline_active_connections", "gauge", "Connections with traffic within the largest rate window");
+    for (transport, count) in &active {
+        writer.sample("crystalline_active_connections", &[("transport", transport)], count);
+    }
+
+    writer.family("crystalline_capture_threads_alive", "gauge", "Capture threads that are still reading packets");
+    writer.sample("crystalline_capture_threads_alive", &[], health.threads_alive.load(Ordering::Relaxed));
+
+    writer.family("crystalline_packets_parsed_total", "counter", "Captured packets parsed into a connection");
+    writer.sample("crystalline_packets_parsed_total", &[], health.packets_parsed.load(Ordering::Relaxed));
+
+    writer.family("crystalline_packets_ignored_total", "counter", "Captured packets without TCP or UDP traffic from or to a local address");
+    writer.sample("crystalline_packets_ignored_total", &[], health.packets_ignored.load(Ordering::Relaxed));
+
+    writer.family("crystalline_packet_parse_errors_total", "counter", "Captured packets that could not be parsed");
+    writer.sample("crystalline_packet_parse_errors_total", &[], health.parse_errors.load(Ordering::Relaxed));
+
+    writer.family("crystalline_process_scans_total", "counter", "Scans of /proc for processes and their sockets");
+    writer.sample("crystalline_process_scans_total", &[], scans.scans.load(Ordering::Relaxed));
+
+    writer.family("crystalline_process_scan_seconds_total", "counter", "Time spent scanning /proc");
+    writer.sample("crystalline_process_scan_seconds_total", &[], seconds(scans.scan_micros.load(Ordering::Relaxed), 1_000_000));
+
+    writer.family("crystalline_process_scan_duration_seconds", "gauge", "Duration of the latest scan of /proc");
+    writer.sample("crystalline_process_scan_duration_seconds", &[], seconds(scans.last_scan_micros.load(Ordering::Relaxed), 1_000_000));
+
+    writer.family("crystalline_process_scan_interval_seconds", "gauge", "Time until the next scan of /proc, longer when scans get expensive");
+    writer.sample("crystalline_process_scan_interval_seconds", &[], seconds(scans.interval_millis.load(Ordering::Relaxed), 1_000));
+
+    writer.family("crystalline_processes_scanned", "gauge", "Processes found by the latest scan of /proc");
+    writer.sample("crystalline_processes_scanned", &[], scans.processes.load(Ordering::Relaxed));
+
+    writer.family("crystalline_process_fd_tables_read_total", "counter", "File descriptor tables read, the rest were unchanged since the previous scan");
+    writer.sample("crystalline_process_fd_tables_read_total", &[], scans.fd_tables_read.load(Ordering::Relaxed));
+
+    writer.output
+}
+
+fn seconds(value: u64, units_per_second: u64) -> f64 {
+    value as f64 / units_per_second as f64
+}
+
+#[utoipa::path(
+    responses((status = 200, description = "Prometheus text exposition format", content_type = "text/plain", body = String)),
+)]
+#[get("/metrics")]
+pub async fn metrics(state: web::Data<Mutex<State>>, health: web::Data<CaptureHealth>, scans: web::Data<ScanHealth>) -> HttpResponse {
+    let body = {
+        let mut state = state.lock().unwrap();
+        state.refresh();
+        render(&state.connections, &state.process_traffic, &health, &scans)
+    };
+
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::time::Duration;
+
+    use super::render;
+    use crate::helpers::testing;
+    use crate::structs::aggregate::ProcessTraffic;
+    use crate::structs::connection::{Connection, TransportType};
+    use crate::structs::health::{CaptureHealth, ScanHealth};
+    use crate::structs::process::ProcessInfo;
+    use crate::structs::rate::Rate;
+
+    fn connection(transport_type: TransportType, local_port: u16, remote: ([u8; 4], u16), bytes_uploaded: usize) -> Connection {
+        Connection {
+            process_id: 42,
+            process_start_time: 100,
+            bytes_uploaded,
+            bytes_downloaded: 10,
+            packets_uploaded: 1,
+            packets_downloaded: 1,
+            ..testing::connection_to(transport_type, local_port, remote)
+        }
+    }
+
+    // An exited process and the one that reused its pid
+    fn processes() -> Vec<ProcessInfo> {
+        vec![
+            ProcessInfo { start_time: 50, exited: true, ..testing::process(42, "/usr/bin/wget") },
+            ProcessInfo { start_time: 100, ..testing::process(42, "/usr/bin/\"curl\"") },
+        ]
+    }
+
+    #[test]
+    fn renders_traffic_by_process_and_remote() {
+        let mut active = connection(TransportType::Tcp, 1000, ([1, 1, 1, 1], 443), 100);
+        active.rates = vec![Rate { window: 1, upload: 1.0, download: 0.0 }];
+        let connections = vec![
+            active,
+            connection(TransportType::Tcp, 1001, ([1, 1, 1, 1], 443), 50),
+            connection(TransportType::Udp, 1002, ([8, 8, 8, 8], 53), 5),
+            Connection { process_start_time: 50, ..connection(TransportType::Tcp, 1003, ([9, 9, 9, 9], 80), 7) },
+        ];
+        let processes = processes();
+        let process_of = |connection: &Connection| processes.iter().find(|process| process.key() == connection.process_key());
+
+        // The exited process' connection was evicted since the first refresh, which must not lower its totals
+        let mut process_traffic = ProcessTraffic::default();
+        process_traffic.record(&connections, process_of);
+        process_traffic.record(&connections[..3].to_vec(), process_of);
+
+        let health = CaptureHealth::default();
+        health.record_remote(IpAddr::from([1, 1, 1, 1]), 100, true);
+        health.record_remote(IpAddr::from([1, 1, 1, 1]), 50, true);
+        health.record_remote(IpAddr::from([8, 8, 8, 8]), 10, false);
+
+        let output = render(&connections, &process_traffic, &health, &ScanHealth::default());
+
+        assert!(output.contains("# TYPE crystalline_process_bytes_total counter\n"));
+        assert!(output.contains("crystalline_process_bytes_total{pid=\"42\",start_time=\"100\",executable=\"/usr/bin/\\\"curl\\\"\",direction=\"upload\"} 155\n"));
+        assert!(output.contains("crystalline_process_packets_total{pid=\"42\",start_time=\"100\",executable=\"/usr/bin/\\\"curl\\\"\",direction=\"download\"} 3\n"));
+        assert!(output.contains("crystalline_process_bytes_total{pid=\"42\",start_time=\"50\",executable=\"/usr/bin/wget\",direction=\"upload\"} 7\n"));
+        assert!(output.contains("# TYPE crystalline_remote_bytes_total counter\n"));
+        assert!(output.contains("crystalline_remote_bytes_total{remote=\"1.1.1.1\",direction=\"upload\"} 150\n"));
+        assert!(output.contains("crystalline_remote_packets_total{remote=\"1.1.1.1\",direction=\"upload\"} 2\n"));
+        assert!(output.contains("crystalline_remote_bytes_total{remote=\"8.8.8.8\",direction=\"download\"} 10\n"));
+        assert!(output.contains("crystalline_connections{transport=\"tcp\"} 3\n"));
+        assert!(output.contains("crystalline_connections{transport=\"udp\"} 1\n"));
+        assert!(output.contains("crystalline_active_connections{transport=\"tcp\"} 1\n"));
+        assert!(output.contains("crystalline_active_connections{transport=\"udp\"} 0\n"));
+    }
+
+    #[test]
+    fn renders_capture_and_scan_health() {
+        let health = CaptureHealth::default();
+        let _alive = health.alive();
+        health.record_packet(true);
+        health.record_packet(true);
+        health.record_packet(false);
+        health.record_ignored();
+
+        let scans = ScanHealth::default();
+        scans.record_processes(120, 7);
+        scans.record_scan(Duration::from_millis(4), Duration::from_millis(200));
+
+        let output = render(&vec![], &ProcessTraffic::default(), &health, &scans);
+
+        assert!(output.contains("crystalline_capture_threads_alive 1\n"));
+        assert!(output.contains("crystalline_packets_parsed_total 2\n"));
+        assert!(output.contains("crystalline_packets_ignored_total 1\n"));
+        assert!(output.contains("crystalline_packet_parse_errors_total 1\n"));
+        assert!(output.contains("crystalline_process_scans_total 1\n"));
+        assert!(output.contains("crystalline_process_scan_duration_seconds 0.004\n"));
+        assert!(output.contains("crystalline_process_scan_interval_seconds 0.2\n"));
+        assert!(output.contains("crystalline_processes_scanned 120\n"));
+        assert!(output.contains("crystalline_process_fd_tables_read_total 7\n"));
+    }
+}
@@ -0,0 +1,70 @@
+use serde_derive::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::structs::aggregate::{aggregate, ProcessAggregates};
+use crate::structs::connection::{sort_connections, Connection, ConnectionSort};
+use crate::structs::process::{ProcessInfo, ProcessInfos};
+use crate::structs::state::State;
+
+pub mod events;
+pub mod metrics;
+pub mod openapi;
+pub mod v1;
+pub mod websocket;
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorResponse {
+    pub error: String,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DeltaQuery {
+    /// Cursor of an earlier response, to only get what changed after it
+    pub since: Option<u64>,
+}
+
+// `reset` and `removed` are only there when the request passed a cursor
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Snapshot<'a> {
+    pub cursor: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reset: Option<bool>,
+    pub connections: Vec<&'a Connection>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub removed: Option<Vec<String>>,
+    #[schema(value_type = std::collections::HashMap<String, ProcessInfo>)]
+    pub processes: ProcessInfos,
+    // Always over every connection, even when only the changed ones are listed
+    pub traffic: ProcessAggregates,
+}
+
+// The body of `GET /`, also pushed as is by the event stream. Given a cursor only the connections changed
+// after it are included, unless the cursor is no longer known and the client has to start over.
+pub fn snapshot(state: &mut State, sort: ConnectionSort, since: Option<u64>) -> Snapshot<'_> {
+    state.refresh();
+    sort_connections(&mut state.connections, sort);
+
+    let state = &*state;
+    let processes = state.filtered_processes();
+    let traffic = aggregate(&state.connections, |connection| state.process_of(connection));
+    let cursor = state.changes.cursor();
+    let all_connections = || state.connections.iter().collect();
+
+    let since = match since {
+        Some(since) => since,
+        None => return Snapshot { cursor, reset: None, connections: all_connections(), removed: None, processes, traffic },
+    };
+
+    match state.changes.since(since) {
+        Some(changes) => Snapshot {
+            cursor,
+            reset: Some(false),
+            connections: state.connections.iter().filter(|connection| changes.changed.contains(&connection.id)).collect(),
+            removed: Some(changes.removed),
+            processes,
+            traffic,
+        },
+        None => Snapshot { cursor, reset: Some(true), connections: all_connections(), removed: Some(vec![]), processes, traffic },
+    }
+}
@@ -0,0 +1,278 @@
+use actix_web::{get, HttpResponse};
+use utoipa::OpenApi;
+
+use crate::api::{events, metrics, v1, websocket, ErrorResponse, Snapshot};
+use crate::history::queries::{Bucket, ConnectionTraffic, HostTraffic, ProcessTraffic, TrafficBucket};
+use crate::structs::aggregate::{
+    ContainerTotals, ExecutableTotals, ProcessAggregates, ProcessTotals, TrafficTotals, UnitTotals, UserTotals,
+};
+use crate::structs::cgroup::{Cgroup, Container, ContainerRuntime};
+use crate::structs::changes::ConnectionChange;
+use crate::structs::connection::{Connection, SortKey, SortOrder, Timestamp, TransportType};
+use crate::structs::process::ProcessInfo;
+use crate::structs::rate::Rate;
+use crate::structs::tree::ProcessNode;
+
+// Generated from the same types the handlers serialize, so it cannot drift from the responses
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Crystalline", description = "Per-process network traffic captured on this host"),
+    paths(
+        crate::index,
+        crate::history_processes,
+        crate::history_hosts,
+        crate::history_connections,
+        crate::history_series,
+        events::events,
+        websocket::websocket,
+        metrics::metrics,
+        v1::list_connections,
+        v1::get_connection,
+        v1::list_processes,
+        v1::get_process_tree,
+        v1::get_process,
+        v1::get_traffic,
+        v1::list_devices,
+        openapi_document,
+    ),
+    components(schemas(
+        Connection, ConnectionChange, ProcessInfo, TransportType, Rate, Timestamp, SortKey, SortOrder,
+        TrafficTotals, ProcessTotals, ExecutableTotals, UserTotals, ContainerTotals, UnitTotals, ProcessAggregates,
+        Cgroup, Container, ContainerRuntime,
+        ProcessNode, Snapshot, ErrorResponse, v1::DeviceInfo, v1::ProcessDetails,
+        Bucket, ProcessTraffic, HostTraffic, ConnectionTraffic, TrafficBucket,
+    )),
+)]
+pub struct ApiDoc;
+
+#[utoipa::path(
+    context_path = "/api/v1",
+    responses((status = 200, description = "This document", content_type = "application/json", body = Object)),
+)]
+#[get("/openapi.json")]
+pub async fn openapi_document() -> HttpResponse {
+    HttpResponse::Ok().json(ApiDoc::openapi())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::Mutex;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, read_body_json, TestRequest};
+    use actix_web::{web, App};
+    use serde_json::{json, Value};
+    use utoipa::OpenApi;
+
+    use super::ApiDoc;
+    use crate::api::v1;
+    use crate::structs::changes::ChangeTracker;
+    use crate::structs::connection::{Connection, TransportType};
+    use crate::structs::process::{ProcessInfo, ProcessInfos, ProcessTable};
+    use crate::structs::rate::Rate;
+    use crate::structs::state::State;
+    use crate::threads::processes::index_inodes;
+
+    fn state() -> State {
+        let mut connection = Connection::new(
+            SocketAddr::from(([192, 168, 1, 10], 40000)),
+            SocketAddr::from(([93, 184, 216, 34], 443)),
+            TransportType::Tcp,
+            UNIX_EPOCH + Duration::from_millis(1_500),
+        );
+        connection.inode = 7;
+        connection.bytes_uploaded = 1_000;
+        connection.rates = vec![Rate { window: 1, upload: 12.5, download: 0.0 }];
+        let unbound = Connection::new(
+            SocketAddr::from(([192, 168, 1, 10], 5353)),
+            SocketAddr::from(([224, 0, 0, 251], 5353)),
+            TransportType::Udp,
+            UNIX_EPOCH,
+        );
+
+        let mut processes = ProcessInfos::new();
+        processes.insert(42, ProcessInfo {
+            pid: 42,
+            command: "curl example.com".to_string(),
+            executable: "/usr/bin/curl".to_string(),
+            inodes: vec![7],
+            ..ProcessInfo::default()
+        });
+
+        let (capture_receiver, capture_updater) = single_value_channel::channel();
+        let (processes_receiver, processes_updater) = single_value_channel::channel();
+        capture_updater.update(Some(vec![connection, unbound])).unwrap();
+        processes_updater.update(Some(ProcessTable { inodes: index_inodes(&processes), processes })).unwrap();
+
+        State::new(capture_receiver, processes_receiver)
+    }
+
+    fn resolve<'a>(spec: &'a Value, schema: &'a Value) -> &'a Value {
+        match schema["$ref"].as_str() {
+            Some(reference) => {
+                let name = reference.trim_start_matches("#/components/schemas/");
+                resolve(spec, &spec["components"]["schemas"][name])
+            }
+            None => schema,
+        }
+    }
+
+    fn matches_type(kind: &str, value: &Value) -> bool {
+        match kind {
+            "null" => value.is_null(),
+            "boolean" => value.is_boolean(),
+            "integer" => value.is_i64() || value.is_u64(),
+            "number" => value.is_number(),
+            "string" => value.is_string(),
+            "array" => value.is_array(),
+            "object" => value.is_object(),
+            _ => false,
+        }
+    }
+
+    // Property names declared by a schema and the parts it combines with allOf
+    fn declared_properties<'a>(spec: &'a Value, schema: &'a Value, names: &mut Vec<&'a str>) {
+        let schema = resolve(spec, schema);
+        names.extend(schema["properties"].as_object().into_iter().flat_map(|properties| properties.keys().map(String::as_str)));
+        for part in schema["allOf"].as_array().into_iter().flatten() {
+            declared_properties(spec, part, names);
+        }
+    }
+
+    // Enough of JSON Schema to cover what utoipa generates for this API, except that objects may not have
+    // properties the schema does not mention unless it allows them with additionalProperties
+    fn validate(spec: &Value, schema: &Value, value: &Value, path: &str) -> Result<(), String> {
+        validate_part(spec, schema, value, path, true)
+    }
+
+    fn validate_part(spec: &Value, schema: &Value, value: &Value, path: &str, closed: bool) -> Result<(), String> {
+        let schema = resolve(spec, schema);
+
+        if let Some(all) = schema["allOf"].as_array() {
+            for part in all {
+                validate_part(spec, part, value, path, false)?;
+            }
+        }
+        if let Some(one) = schema["oneOf"].as_array() {
+            let matching = one.iter().filter(|part| validate(spec, part, value, path).is_ok()).count();
+            if matching != 1 {
+                return Err(format!("{}: {} matches {} of the oneOf schemas", path, value, matching));
+            }
+        }
+
+        let types: Vec<&str> = match &schema["type"] {
+            Value::String(kind) => vec![kind.as_str()],
+            Value::Array(kinds) => kinds.iter().filter_map(Value::as_str).collect(),
+            _ => vec![],
+        };
+        if !types.is_empty() && !types.iter().any(|kind| matches_type(kind, value)) {
+            return Err(format!("{}: {} is not of type {:?}", path, value, types));
+        }
+
+        if let Some(variants) = schema["enum"].as_array() {
+            if !variants.contains(value) {
+                return Err(format!("{}: {} is not one of {:?}", path, value, variants));
+            }
+        }
+
+        if let Value::Object(object) = value {
+            for required in schema["required"].as_array().into_iter().flatten().filter_map(Value::as_str) {
+                if !object.contains_key(required) {
+                    return Err(format!("{}: missing required property {}", path, required));
+                }
+            }
+
+            let mut declared = vec![];
+            declared_properties(spec, schema, &mut declared);
+            for (key, property) in object {
+                let property_path = format!("{}.{}", path, key);
+                match &schema["properties"][key] {
+                    Value::Null => {}
+                    property_schema => validate(spec, property_schema, property, &property_path)?,
+                }
+                if schema["additionalProperties"].is_object() && !declared.contains(&key.as_str()) {
+                    validate(spec, &schema["additionalProperties"], property, &property_path)?;
+                } else if closed && !declared.is_empty() && !declared.contains(&key.as_str()) {
+                    return Err(format!("{}: property is not in the schema", property_path));
+                }
+            }
+        }
+
+        if let (Value::Array(items), Some(item_schema)) = (value, schema.get("items")) {
+            for (index, item) in items.iter().enumerate() {
+                validate(spec, item_schema, item, &format!("{}[{}]", path, index))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn response_schema<'a>(spec: &'a Value, path: &str, status: &str) -> &'a Value {
+        let schema = &spec["paths"][path]["get"]["responses"][status]["content"]["application/json"]["schema"];
+        assert!(!schema.is_null(), "no {} response documented for {}", status, path);
+        schema
+    }
+
+    #[actix_web::test]
+    async fn served_document_describes_responses() {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(Mutex::new(state())))
+                .service(crate::index)
+                .configure(v1::configure)
+        ).await;
+
+        let spec: Value = call_and_read_body_json(&app, TestRequest::get().uri("/api/v1/openapi.json").to_request()).await;
+        assert!(spec["openapi"].as_str().unwrap().starts_with("3."));
+
+        let id = {
+            let page: Value = call_and_read_body_json(&app, TestRequest::get().uri("/api/v1/connections").to_request()).await;
+            page["items"][0]["id"].as_str().unwrap().to_string()
+        };
+
+        let requests = [
+            ("/", "/", "200"),
+            ("/?since=0", "/", "200"),
+            ("/api/v1/connections", "/api/v1/connections", "200"),
+            (&format!("/api/v1/connections/{}", id), "/api/v1/connections/{id}", "200"),
+            ("/api/v1/connections/missing", "/api/v1/connections/{id}", "404"),
+            ("/api/v1/processes", "/api/v1/processes", "200"),
+            ("/api/v1/processes/42", "/api/v1/processes/{pid}", "200"),
+            ("/api/v1/processes/1", "/api/v1/processes/{pid}", "404"),
+            ("/api/v1/traffic", "/api/v1/traffic", "200"),
+            ("/api/v1/processes/tree?all=true", "/api/v1/processes/tree", "200"),
+        ];
+
+        for (uri, path, status) in requests.iter() {
+            let response = call_service(&app, TestRequest::get().uri(uri).to_request()).await;
+            assert_eq!(response.status().as_str(), *status, "{}", uri);
+
+            let body: Value = read_body_json(response).await;
+            if let Err(error) = validate(&spec, response_schema(&spec, path, status), &body, uri) {
+                panic!("{}", error);
+            }
+        }
+    }
+
+    #[test]
+    fn describes_change_events() {
+        let spec = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let schema = json!({"$ref": "#/components/schemas/ConnectionChange"});
+
+        let mut state = state();
+        state.refresh();
+        let mut tracker = ChangeTracker::default();
+        let mut changes = tracker.changes(state.connections.iter());
+        state.connections[1].rates = vec![Rate { window: 1, upload: 0.0, download: 3.0 }];
+        changes.extend(tracker.changes(state.connections.iter().skip(1)));
+        assert_eq!(changes.len(), 4);
+
+        for change in changes {
+            let value = serde_json::to_value(&change).unwrap();
+            if let Err(error) = validate(&spec, &schema, &value, "change") {
+                panic!("{}", error);
+            }
+        }
+    }
+}
@@ -0,0 +1,245 @@
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use actix_web::{get, web, HttpResponse};
+use libc::pid_t;
+use pcap::Device;
+use serde_derive::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::api::ErrorResponse;
+use crate::api::openapi::openapi_document;
+use crate::structs::aggregate::{aggregate, ProcessAggregates};
+use crate::structs::config::Config;
+use crate::structs::connection::{sort_connections, Connection, ConnectionSort};
+use crate::structs::filter::ConnectionFilter;
+use crate::structs::process::ProcessInfo;
+use crate::structs::state::State;
+use crate::structs::tree::{process_tree, ProcessNode};
+use crate::threads::capture::is_monitored;
+
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 10_000;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct Pagination {
+    pub offset: Option<usize>,
+    /// Defaults to 100, at most 10000
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Page<'a, T> {
+    pub total: usize,
+    pub offset: usize,
+    pub limit: usize,
+    pub items: &'a [T],
+}
+
+impl Pagination {
+    pub fn offset(&self) -> usize {
+        self.offset.unwrap_or(0)
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE)
+    }
+
+    pub fn page<'a, T>(&self, items: &'a [T]) -> Page<'a, T> {
+        let start = self.offset().min(items.len());
+        let end = start.saturating_add(self.limit()).min(items.len());
+
+        Page {
+            total: items.len(),
+            offset: self.offset(),
+            limit: self.limit(),
+            items: &items[start..end],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TreeQuery {
+    /// Also include processes without connections anywhere below them
+    pub all: Option<bool>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DeviceInfo {
+    pub name: String,
+    pub description: Option<String>,
+    #[schema(value_type = Vec<String>)]
+    pub addresses: Vec<IpAddr>,
+    pub loopback: bool,
+    pub up: bool,
+    pub running: bool,
+    pub monitored: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ProcessDetails<'a> {
+    pub process: ProcessInfo,
+    pub connections: Vec<&'a Connection>,
+}
+
+pub fn configure(service_config: &mut web::ServiceConfig) {
+    service_config.service(
+        web::scope("/api/v1")
+            .service(list_connections)
+            .service(get_connection)
+            .service(list_processes)
+            .service(get_process_tree)
+            .service(get_process)
+            .service(get_traffic)
+            .service(list_devices)
+            .service(openapi_document)
+    );
+}
+
+fn not_found(message: String) -> HttpResponse {
+    HttpResponse::NotFound().json(ErrorResponse { error: message })
+}
+
+#[utoipa::path(
+    context_path = "/api/v1",
+    params(ConnectionFilter, ConnectionSort, Pagination),
+    responses((status = 200, body = Page<Connection>)),
+)]
+#[get("/connections")]
+pub async fn list_connections(state: web::Data<Mutex<State>>, filter: web::Query<ConnectionFilter>, sort: web::Query<ConnectionSort>, pagination: web::Query<Pagination>) -> HttpResponse {
+    let mut state = state.lock().unwrap();
+    state.refresh();
+    sort_connections(&mut state.connections, sort.into_inner());
+
+    let connections: Vec<&Connection> = state.connections.iter().filter(|connection| filter.matches(connection)).collect();
+
+    HttpResponse::Ok().json(pagination.page(&connections))
+}
+
+#[utoipa::path(
+    context_path = "/api/v1",
+    params(("id" = String, Path, description = "Connection ID")),
+    responses((status = 200, body = Connection), (status = 404, body = ErrorResponse)),
+)]
+#[get("/connections/{id}")]
+pub async fn get_connection(state: web::Data<Mutex<State>>, id: web::Path<String>) -> HttpResponse {
+    let mut state = state.lock().unwrap();
+    state.refresh();
+
+    match state.connections.iter().find(|connection| connection.id == *id) {
+        Some(connection) => HttpResponse::Ok().json(connection),
+        None => not_found(format!("No connection with id {}", id)),
+    }
+}
+
+#[utoipa::path(
+    context_path = "/api/v1",
+    params(Pagination),
+    responses((status = 200, body = Page<ProcessInfo>)),
+)]
+#[get("/processes")]
+pub async fn list_processes(state: web::Data<Mutex<State>>, pagination: web::Query<Pagination>) -> HttpResponse {
+    let mut state = state.lock().unwrap();
+    state.refresh();
+
+    let mut processes: Vec<ProcessInfo> = state.filtered_processes().into_values().collect();
+    processes.sort_unstable_by_key(|process| process.pid);
+
+    HttpResponse::Ok().json(pagination.page(&processes))
+}
+
+#[utoipa::path(
+    context_path = "/api/v1",
+    params(TreeQuery),
+    responses((status = 200, description = "Root processes, with traffic summed up through their descendants", body = Vec<ProcessNode>)),
+)]
+#[get("/processes/tree")]
+pub async fn get_process_tree(state: web::Data<Mutex<State>>, query: web::Query<TreeQuery>) -> HttpResponse {
+    let mut state = state.lock().unwrap();
+    state.refresh();
+
+    let processes = state.processes.values().chain(state.exited.values());
+    let tree = process_tree(processes, &state.connections, |connection| state.process_of(connection), query.all.unwrap_or(false));
+
+    HttpResponse::Ok().json(tree)
+}
+
+#[utoipa::path(
+    context_path = "/api/v1",
+    params(("pid" = i32, Path, description = "Process ID"), ConnectionSort),
+    responses((status = 200, body = ProcessDetails), (status = 404, body = ErrorResponse)),
+)]
+#[get("/processes/{pid}")]
+pub async fn get_process(state: web::Data<Mutex<State>>, pid: web::Path<pid_t>, sort: web::Query<ConnectionSort>) -> HttpResponse {
+    let pid = pid.into_inner();
+    let mut state = state.lock().unwrap();
+    state.refresh();
+    sort_connections(&mut state.connections, sort.into_inner());
+
+    let process = match state.filtered_processes().remove(&pid).or_else(|| state.processes.get(&pid).cloned()) {
+        Some(process) => process,
+        None => return not_found(format!("No process with pid {}", pid)),
+    };
+    let connections = state.connections.iter().filter(|connection| connection.process_key() == process.key()).collect();
+
+    HttpResponse::Ok().json(ProcessDetails { process, connections })
+}
+
+#[utoipa::path(
+    context_path = "/api/v1",
+    responses((status = 200, body = ProcessAggregates)),
+)]
+#[get("/traffic")]
+pub async fn get_traffic(state: web::Data<Mutex<State>>) -> HttpResponse {
+    let mut state = state.lock().unwrap();
+    state.refresh();
+
+    HttpResponse::Ok().json(aggregate(&state.connections, |connection| state.process_of(connection)))
+}
+
+#[utoipa::path(
+    context_path = "/api/v1",
+    responses((status = 200, body = Vec<DeviceInfo>), (status = 500, body = ErrorResponse)),
+)]
+#[get("/devices")]
+pub async fn list_devices(config: web::Data<Config>) -> HttpResponse {
+    let devices = match Device::list() {
+        Ok(devices) => devices,
+        Err(error) => return HttpResponse::InternalServerError().json(ErrorResponse { error: error.to_string() }),
+    };
+
+    let devices: Vec<DeviceInfo> = devices.into_iter().map(|device| DeviceInfo {
+        monitored: config.capture_file.is_none() && is_monitored(&device, &config.device_name),
+        addresses: device.addresses.iter().map(|address| address.addr).collect(),
+        loopback: device.flags.is_loopback(),
+        up: device.flags.is_up(),
+        running: device.flags.is_running(),
+        name: device.name,
+        description: device.desc,
+    }).collect();
+
+    HttpResponse::Ok().json(devices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Pagination;
+
+    #[test]
+    fn pages_through_items() {
+        let items: Vec<u32> = (0..250).collect();
+
+        let first = Pagination::default().page(&items);
+        assert_eq!((first.total, first.offset, first.limit, first.items.len()), (250, 0, 100, 100));
+
+        let last = Pagination { offset: Some(200), limit: Some(100) }.page(&items);
+        assert_eq!(last.items, &items[200..]);
+
+        let beyond = Pagination { offset: Some(300), limit: None }.page(&items);
+        assert!(beyond.items.is_empty());
+
+        assert_eq!(Pagination { offset: None, limit: Some(usize::MAX) }.limit(), 10_000);
+    }
+}
@@ -0,0 +1,115 @@
+use std::pin::pin;
+use std::sync::Mutex;
+
+use actix_web::{get, rt, web, HttpRequest, HttpResponse};
+use actix_ws::{Message, Session};
+use futures_util::future::{select, Either};
+use serde_derive::Deserialize;
+use serde_json::json;
+
+use crate::api::events::EventsQuery;
+use crate::structs::changes::ChangeTracker;
+use crate::structs::filter::ConnectionFilter;
+use crate::structs::state::State;
+
+// Sent by clients as JSON text frames; a new subscription replaces the previous one
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    Subscribe {
+        #[serde(default)]
+        filter: ConnectionFilter,
+    },
+    Unsubscribe,
+}
+
+// Pushes add/update/remove events for the connections matching the client's subscription, one batch per tick
+#[utoipa::path(
+    params(EventsQuery),
+    responses((status = 101, description = "Switches to a WebSocket; clients send `subscribe`/`unsubscribe` messages and receive arrays of `ConnectionChange`")),
+)]
+#[get("/ws")]
+pub async fn websocket(request: HttpRequest, body: web::Payload, state: web::Data<Mutex<State>>, query: web::Query<EventsQuery>) -> actix_web::Result<HttpResponse> {
+    let (response, mut session, mut messages) = actix_ws::handle(&request, body)?;
+    let mut ticks = rt::time::interval(query.interval());
+
+    rt::spawn(async move {
+        let mut subscription: Option<(ConnectionFilter, ChangeTracker)> = None;
+
+        loop {
+            let message = match select(pin!(ticks.tick()), pin!(messages.recv())).await {
+                Either::Left(_) => None,
+                Either::Right((Some(Ok(message)), _)) => Some(message),
+                Either::Right(_) => break,
+            };
+
+            let result = match message {
+                None => send_changes(&mut session, &state, &mut subscription).await,
+                Some(Message::Text(text)) => match serde_json::from_str(&text) {
+                    Ok(ClientMessage::Subscribe { filter }) => {
+                        subscription = Some((filter, ChangeTracker::default()));
+                        send_changes(&mut session, &state, &mut subscription).await
+                    }
+                    Ok(ClientMessage::Unsubscribe) => {
+                        subscription = None;
+                        Ok(())
+                    }
+                    Err(error) => session.text(json!({"type": "error", "message": error.to_string()}).to_string()).await,
+                },
+                Some(Message::Ping(bytes)) => session.pong(&bytes).await,
+                Some(Message::Close(reason)) => {
+                    let _ = session.close(reason).await;
+                    return;
+                }
+                Some(_) => Ok(()),
+            };
+
+            if result.is_err() {
+                break;
+            }
+        }
+
+        let _ = session.close(None).await;
+    });
+
+    Ok(response)
+}
+
+async fn send_changes(session: &mut Session, state: &Mutex<State>, subscription: &mut Option<(ConnectionFilter, ChangeTracker)>) -> Result<(), actix_ws::Closed> {
+    let (filter, tracker) = match subscription {
+        Some(subscription) => subscription,
+        None => return Ok(()),
+    };
+
+    let changes = {
+        let mut state = state.lock().unwrap();
+        state.refresh();
+        tracker.changes(state.connections.iter().filter(|connection| filter.matches(connection)))
+    };
+    if changes.is_empty() {
+        return Ok(());
+    }
+
+    session.text(serde_json::to_string(&changes).unwrap_or_default()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ClientMessage;
+    use crate::structs::connection::TransportType;
+    use crate::structs::filter::ConnectionFilter;
+
+    #[test]
+    fn parses_subscriptions() {
+        let message: ClientMessage = serde_json::from_str(r#"{"type": "subscribe", "filter": {"transport_type": "Udp", "destination_port": 443}}"#).unwrap();
+        assert_eq!(message, ClientMessage::Subscribe {
+            filter: ConnectionFilter { transport_type: Some(TransportType::Udp), destination_port: Some(443), ..ConnectionFilter::default() },
+        });
+
+        let message: ClientMessage = serde_json::from_str(r#"{"type": "subscribe"}"#).unwrap();
+        assert_eq!(message, ClientMessage::Subscribe { filter: ConnectionFilter::default() });
+
+        let message: ClientMessage = serde_json::from_str(r#"{"type": "unsubscribe"}"#).unwrap();
+        assert_eq!(message, ClientMessage::Unsubscribe);
+    }
+}
@@ -1,2 +1,5 @@
 pub mod display;
 pub mod debug;
+pub mod users;
+#[cfg(test)]
+pub mod testing;
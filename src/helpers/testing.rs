@@ -0,0 +1,38 @@
+use std::net::SocketAddr;
+use std::time::UNIX_EPOCH;
+
+use libc::pid_t;
+
+use crate::structs::connection::{Connection, TransportType};
+use crate::structs::process::{ProcessInfo, ProcessInfos, ProcessTable};
+use crate::threads::processes::index_inodes;
+
+// A TCP connection from 10.0.0.1:<local_port> to 1.1.1.1:443 first seen at the epoch, for tests to set the fields
+// they care about on
+pub fn connection(local_port: u16) -> Connection {
+    connection_to(TransportType::Tcp, local_port, ([1, 1, 1, 1], 443))
+}
+
+pub fn connection_to(transport_type: TransportType, local_port: u16, remote: ([u8; 4], u16)) -> Connection {
+    Connection::new(SocketAddr::from(([10, 0, 0, 1], local_port)), SocketAddr::from(remote), transport_type, UNIX_EPOCH)
+}
+
+pub fn process(pid: pid_t, executable: &str) -> ProcessInfo {
+    ProcessInfo {
+        pid,
+        command: format!("{} --pid {}", executable, pid),
+        executable: executable.to_string(),
+        ..ProcessInfo::default()
+    }
+}
+
+pub fn by_pid(processes: Vec<ProcessInfo>) -> ProcessInfos {
+    processes.into_iter().map(|process| (process.pid, process)).collect()
+}
+
+// What the processes thread sends after a scan
+pub fn process_table(processes: Vec<ProcessInfo>) -> ProcessTable {
+    let processes = by_pid(processes);
+
+    ProcessTable { inodes: index_inodes(&processes), processes }
+}
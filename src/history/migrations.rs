@@ -0,0 +1,130 @@
+use rusqlite::Connection;
+
+// Each entry upgrades the schema by one version, tracked in SQLite's `user_version`
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE connection_samples (
+        id INTEGER PRIMARY KEY,
+        sampled_at INTEGER NOT NULL,
+        transport_type TEXT NOT NULL,
+        local_address TEXT NOT NULL,
+        remote_address TEXT NOT NULL,
+        process_id INTEGER NOT NULL,
+        executable TEXT NOT NULL,
+        bytes_uploaded INTEGER NOT NULL,
+        bytes_downloaded INTEGER NOT NULL,
+        packets_uploaded INTEGER NOT NULL,
+        packets_downloaded INTEGER NOT NULL
+    );
+    CREATE INDEX connection_samples_sampled_at ON connection_samples (sampled_at);
+
+    CREATE TABLE process_samples (
+        id INTEGER PRIMARY KEY,
+        sampled_at INTEGER NOT NULL,
+        process_id INTEGER NOT NULL,
+        executable TEXT NOT NULL,
+        command TEXT NOT NULL,
+        bytes_uploaded INTEGER NOT NULL,
+        bytes_downloaded INTEGER NOT NULL
+    );
+    CREATE INDEX process_samples_sampled_at ON process_samples (sampled_at);",
+    "CREATE TABLE connection_rollups (
+        resolution INTEGER NOT NULL,
+        bucket_start INTEGER NOT NULL,
+        transport_type TEXT NOT NULL,
+        local_address TEXT NOT NULL,
+        remote_address TEXT NOT NULL,
+        process_id INTEGER NOT NULL,
+        executable TEXT NOT NULL,
+        bytes_uploaded INTEGER NOT NULL,
+        bytes_downloaded INTEGER NOT NULL,
+        packets_uploaded INTEGER NOT NULL,
+        packets_downloaded INTEGER NOT NULL,
+        PRIMARY KEY (resolution, bucket_start, transport_type, local_address, remote_address, process_id, executable)
+    );
+
+    CREATE TABLE process_rollups (
+        resolution INTEGER NOT NULL,
+        bucket_start INTEGER NOT NULL,
+        process_id INTEGER NOT NULL,
+        executable TEXT NOT NULL,
+        command TEXT NOT NULL,
+        bytes_uploaded INTEGER NOT NULL,
+        bytes_downloaded INTEGER NOT NULL,
+        PRIMARY KEY (resolution, bucket_start, process_id, executable)
+    );
+
+    CREATE TABLE retention_state (
+        tier TEXT PRIMARY KEY,
+        rolled_up_to INTEGER NOT NULL DEFAULT 0,
+        retained_from INTEGER NOT NULL DEFAULT 0
+    );
+    INSERT INTO retention_state (tier) VALUES ('raw'), ('hourly'), ('daily');",
+];
+
+pub fn schema_version(database: &Connection) -> rusqlite::Result<usize> {
+    database.pragma_query_value(None, "user_version", |row| row.get(0))
+}
+
+pub fn migrate(database: &mut Connection) -> rusqlite::Result<()> {
+    let current_version = schema_version(database)?;
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(current_version) {
+        let transaction = database.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", index + 1)?;
+        transaction.commit()?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use super::{migrate, schema_version, MIGRATIONS};
+
+    fn table_names(database: &Connection) -> Vec<String> {
+        let mut statement = database.prepare("SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name").unwrap();
+        let names = statement.query_map([], |row| row.get(0)).unwrap();
+
+        names.map(|name| name.unwrap()).collect()
+    }
+
+    #[test]
+    fn migrates_empty_database_to_latest_version() {
+        let mut database = Connection::open_in_memory().unwrap();
+
+        migrate(&mut database).unwrap();
+
+        assert_eq!(schema_version(&database).unwrap(), MIGRATIONS.len());
+        assert_eq!(table_names(&database), vec![
+            "connection_rollups",
+            "connection_samples",
+            "process_rollups",
+            "process_samples",
+            "retention_state",
+        ]);
+    }
+
+    #[test]
+    fn migrating_twice_is_a_no_op() {
+        let mut database = Connection::open_in_memory().unwrap();
+
+        migrate(&mut database).unwrap();
+        migrate(&mut database).unwrap();
+
+        assert_eq!(schema_version(&database).unwrap(), MIGRATIONS.len());
+    }
+
+    #[test]
+    fn resumes_from_recorded_version() {
+        let mut database = Connection::open_in_memory().unwrap();
+        database.execute_batch(MIGRATIONS[0]).unwrap();
+        database.pragma_update(None, "user_version", 1).unwrap();
+
+        migrate(&mut database).unwrap();
+
+        assert_eq!(schema_version(&database).unwrap(), MIGRATIONS.len());
+    }
+}
@@ -0,0 +1,5 @@
+pub mod migrations;
+pub mod queries;
+pub mod retention;
+pub mod samples;
+pub mod store;
@@ -0,0 +1,360 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use libc::pid_t;
+use rusqlite::named_params;
+use serde_derive::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::history::store::HistoryStore;
+
+const DEFAULT_RANGE: u64 = 24 * 60 * 60;
+const DEFAULT_LIMIT: usize = 20;
+
+// Raw samples where they are still kept, hourly rollups before that and daily rollups for the oldest periods
+const PROCESS_TRAFFIC: &str = "(
+    SELECT sampled_at, process_id, executable, command, bytes_uploaded, bytes_downloaded
+    FROM process_samples
+    WHERE sampled_at >= :raw_from
+    UNION ALL
+    SELECT bucket_start, process_id, executable, command, bytes_uploaded, bytes_downloaded
+    FROM process_rollups
+    WHERE resolution = 3600 AND bucket_start >= :hourly_from AND bucket_start < :raw_from
+    UNION ALL
+    SELECT bucket_start, process_id, executable, command, bytes_uploaded, bytes_downloaded
+    FROM process_rollups
+    WHERE resolution = 86400 AND bucket_start < :hourly_from
+)";
+
+const CONNECTION_TRAFFIC: &str = "(
+    SELECT sampled_at, transport_type, local_address, remote_address, process_id, executable,
+        bytes_uploaded, bytes_downloaded, packets_uploaded, packets_downloaded
+    FROM connection_samples
+    WHERE sampled_at >= :raw_from
+    UNION ALL
+    SELECT bucket_start, transport_type, local_address, remote_address, process_id, executable,
+        bytes_uploaded, bytes_downloaded, packets_uploaded, packets_downloaded
+    FROM connection_rollups
+    WHERE resolution = 3600 AND bucket_start >= :hourly_from AND bucket_start < :raw_from
+    UNION ALL
+    SELECT bucket_start, transport_type, local_address, remote_address, process_id, executable,
+        bytes_uploaded, bytes_downloaded, packets_uploaded, packets_downloaded
+    FROM connection_rollups
+    WHERE resolution = 86400 AND bucket_start < :hourly_from
+)";
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Bucket {
+    Minute,
+    Hour,
+    Day,
+}
+
+impl Bucket {
+    pub fn seconds(&self) -> u64 {
+        match self {
+            Bucket::Minute => 60,
+            Bucket::Hour => 60 * 60,
+            Bucket::Day => 24 * 60 * 60,
+        }
+    }
+}
+
+// Query string shared by the history endpoints; timestamps are unix seconds
+#[derive(Clone, Copy, Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct HistoryQuery {
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    pub limit: Option<usize>,
+    pub bucket: Option<Bucket>,
+}
+
+impl HistoryQuery {
+    // Defaults to the last 24 hours
+    pub fn range(&self) -> (u64, u64) {
+        let to = self.to.unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs());
+        let from = self.from.unwrap_or(to.saturating_sub(DEFAULT_RANGE));
+
+        (from, to)
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit.unwrap_or(DEFAULT_LIMIT)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, ToSchema)]
+pub struct ProcessTraffic {
+    #[schema(value_type = i32)]
+    pub process_id: pid_t,
+    pub executable: String,
+    pub command: String,
+    pub bytes_uploaded: u64,
+    pub bytes_downloaded: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, ToSchema)]
+pub struct HostTraffic {
+    #[schema(value_type = String)]
+    pub host: IpAddr,
+    pub bytes_uploaded: u64,
+    pub bytes_downloaded: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, ToSchema)]
+pub struct ConnectionTraffic {
+    pub transport_type: String,
+    pub local_address: String,
+    pub remote_address: String,
+    #[schema(value_type = i32)]
+    pub process_id: pid_t,
+    pub executable: String,
+    pub bytes_uploaded: u64,
+    pub bytes_downloaded: u64,
+    pub packets_uploaded: u64,
+    pub packets_downloaded: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, ToSchema)]
+pub struct TrafficBucket {
+    pub start: u64,
+    pub bytes_uploaded: u64,
+    pub bytes_downloaded: u64,
+}
+
+impl HistoryStore {
+    pub fn top_processes(&self, query: &HistoryQuery) -> rusqlite::Result<Vec<ProcessTraffic>> {
+        let (from, to) = query.range();
+        let coverage = self.coverage()?;
+        let mut statement = self.database.prepare_cached(&format!(
+            "SELECT process_id, executable, MAX(command), SUM(bytes_uploaded), SUM(bytes_downloaded)
+            FROM {}
+            WHERE sampled_at >= :from AND sampled_at < :to
+            GROUP BY process_id, executable
+            ORDER BY SUM(bytes_uploaded) + SUM(bytes_downloaded) DESC
+            LIMIT :limit",
+            PROCESS_TRAFFIC,
+        ))?;
+        let parameters = named_params! {
+            ":from": from,
+            ":to": to,
+            ":limit": query.limit(),
+            ":raw_from": coverage.raw_from,
+            ":hourly_from": coverage.hourly_from,
+        };
+        let rows = statement.query_map(parameters, |row| Ok(ProcessTraffic {
+            process_id: row.get(0)?,
+            executable: row.get(1)?,
+            command: row.get(2)?,
+            bytes_uploaded: row.get(3)?,
+            bytes_downloaded: row.get(4)?,
+        }))?;
+
+        rows.collect()
+    }
+
+    pub fn top_hosts(&self, query: &HistoryQuery) -> rusqlite::Result<Vec<HostTraffic>> {
+        let (from, to) = query.range();
+        let coverage = self.coverage()?;
+        let mut statement = self.database.prepare_cached(&format!(
+            "SELECT remote_address, SUM(bytes_uploaded), SUM(bytes_downloaded)
+            FROM {}
+            WHERE sampled_at >= :from AND sampled_at < :to
+            GROUP BY remote_address",
+            CONNECTION_TRAFFIC,
+        ))?;
+        let parameters = named_params! {
+            ":from": from,
+            ":to": to,
+            ":raw_from": coverage.raw_from,
+            ":hourly_from": coverage.hourly_from,
+        };
+        let rows = statement.query_map(parameters, |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, u64>(1)?, row.get::<_, u64>(2)?))
+        })?;
+
+        // Addresses are stored with their port, so ports of the same host are merged here
+        let mut hosts: HashMap<IpAddr, HostTraffic> = HashMap::new();
+        for row in rows {
+            let (remote_address, bytes_uploaded, bytes_downloaded) = row?;
+            let host = match remote_address.parse::<SocketAddr>() {
+                Ok(address) => address.ip(),
+                Err(_) => continue,
+            };
+
+            let traffic = hosts.entry(host).or_insert(HostTraffic { host, bytes_uploaded: 0, bytes_downloaded: 0 });
+            traffic.bytes_uploaded += bytes_uploaded;
+            traffic.bytes_downloaded += bytes_downloaded;
+        }
+
+        let mut hosts: Vec<HostTraffic> = hosts.into_values().collect();
+        hosts.sort_unstable_by_key(|host| std::cmp::Reverse(host.bytes_uploaded + host.bytes_downloaded));
+        hosts.truncate(query.limit());
+
+        Ok(hosts)
+    }
+
+    pub fn connection_totals(&self, query: &HistoryQuery) -> rusqlite::Result<Vec<ConnectionTraffic>> {
+        let (from, to) = query.range();
+        let coverage = self.coverage()?;
+        let mut statement = self.database.prepare_cached(&format!(
+            "SELECT transport_type, local_address, remote_address, process_id, executable,
+                SUM(bytes_uploaded), SUM(bytes_downloaded), SUM(packets_uploaded), SUM(packets_downloaded)
+            FROM {}
+            WHERE sampled_at >= :from AND sampled_at < :to
+            GROUP BY transport_type, local_address, remote_address, process_id, executable
+            ORDER BY SUM(bytes_uploaded) + SUM(bytes_downloaded) DESC
+            LIMIT :limit",
+            CONNECTION_TRAFFIC,
+        ))?;
+        let parameters = named_params! {
+            ":from": from,
+            ":to": to,
+            ":limit": query.limit(),
+            ":raw_from": coverage.raw_from,
+            ":hourly_from": coverage.hourly_from,
+        };
+        let rows = statement.query_map(parameters, |row| Ok(ConnectionTraffic {
+            transport_type: row.get(0)?,
+            local_address: row.get(1)?,
+            remote_address: row.get(2)?,
+            process_id: row.get(3)?,
+            executable: row.get(4)?,
+            bytes_uploaded: row.get(5)?,
+            bytes_downloaded: row.get(6)?,
+            packets_uploaded: row.get(7)?,
+            packets_downloaded: row.get(8)?,
+        }))?;
+
+        rows.collect()
+    }
+
+    // Buckets finer than the resolution still kept for a period get that period's traffic in their first bucket
+    pub fn time_series(&self, query: &HistoryQuery) -> rusqlite::Result<Vec<TrafficBucket>> {
+        let (from, to) = query.range();
+        let bucket_size = query.bucket.unwrap_or(Bucket::Hour).seconds();
+        let coverage = self.coverage()?;
+        let mut statement = self.database.prepare_cached(&format!(
+            "SELECT sampled_at / :bucket_size * :bucket_size AS bucket, SUM(bytes_uploaded), SUM(bytes_downloaded)
+            FROM {}
+            WHERE sampled_at >= :from AND sampled_at < :to
+            GROUP BY bucket
+            ORDER BY bucket",
+            PROCESS_TRAFFIC,
+        ))?;
+        let parameters = named_params! {
+            ":from": from,
+            ":to": to,
+            ":bucket_size": bucket_size,
+            ":raw_from": coverage.raw_from,
+            ":hourly_from": coverage.hourly_from,
+        };
+        let rows = statement.query_map(parameters, |row| Ok(TrafficBucket {
+            start: row.get(0)?,
+            bytes_uploaded: row.get(1)?,
+            bytes_downloaded: row.get(2)?,
+        }))?;
+
+        rows.collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::{Bucket, HistoryQuery, TrafficBucket};
+    use crate::history::samples::{ConnectionSample, ProcessSample};
+    use crate::history::store::HistoryStore;
+    use crate::structs::connection::TransportType;
+
+    const START: u64 = 1_700_000_000 / 3600 * 3600;
+
+    fn connection(remote: ([u8; 4], u16), process_id: i32, bytes_uploaded: usize, bytes_downloaded: usize) -> ConnectionSample {
+        ConnectionSample {
+            transport_type: TransportType::Tcp,
+            local_address: SocketAddr::from(([10, 0, 0, 1], 40000)),
+            remote_address: SocketAddr::from(remote),
+            process_id,
+            executable: format!("/usr/bin/{}", process_id),
+            bytes_uploaded,
+            bytes_downloaded,
+            packets_uploaded: 1,
+            packets_downloaded: 1,
+        }
+    }
+
+    fn process(process_id: i32, bytes_uploaded: usize, bytes_downloaded: usize) -> ProcessSample {
+        ProcessSample {
+            process_id,
+            executable: format!("/usr/bin/{}", process_id),
+            command: format!("{} --flag", process_id),
+            bytes_uploaded,
+            bytes_downloaded,
+        }
+    }
+
+    fn store() -> HistoryStore {
+        let mut store = HistoryStore::open_in_memory().unwrap();
+        store.record(START, &[
+            connection(([1, 1, 1, 1], 443), 1, 100, 1000),
+            connection(([1, 1, 1, 1], 80), 1, 100, 0),
+            connection(([8, 8, 8, 8], 53), 2, 50, 50),
+        ], &[process(1, 200, 1000), process(2, 50, 50)]).unwrap();
+        store.record(START + 60, &[connection(([8, 8, 8, 8], 53), 2, 5000, 0)], &[process(2, 5000, 0)]).unwrap();
+        store.record(START + 3600, &[connection(([9, 9, 9, 9], 443), 3, 1, 1)], &[process(3, 1, 1)]).unwrap();
+        store
+    }
+
+    fn range(from: u64, to: u64) -> HistoryQuery {
+        HistoryQuery { from: Some(from), to: Some(to), ..HistoryQuery::default() }
+    }
+
+    #[test]
+    fn ranks_processes_within_range() {
+        let processes = store().top_processes(&range(START, START + 3600)).unwrap();
+
+        assert_eq!(processes.iter().map(|process| process.process_id).collect::<Vec<i32>>(), vec![2, 1]);
+        assert_eq!(processes[0].bytes_uploaded, 5050);
+        assert_eq!(processes[0].command, "2 --flag");
+    }
+
+    #[test]
+    fn merges_ports_of_remote_hosts() {
+        let hosts = store().top_hosts(&range(START, START + 60)).unwrap();
+
+        assert_eq!(hosts.len(), 2);
+        assert_eq!(hosts[0].host.to_string(), "1.1.1.1");
+        assert_eq!(hosts[0].bytes_uploaded, 200);
+        assert_eq!(hosts[0].bytes_downloaded, 1000);
+    }
+
+    #[test]
+    fn sums_connections_and_applies_limit() {
+        let query = HistoryQuery { limit: Some(1), ..range(START, START + 7200) };
+        let connections = store().connection_totals(&query).unwrap();
+
+        assert_eq!(connections.len(), 1);
+        assert_eq!(connections[0].remote_address, "8.8.8.8:53");
+        assert_eq!(connections[0].bytes_uploaded, 5050);
+        assert_eq!(connections[0].packets_uploaded, 2);
+    }
+
+    #[test]
+    fn buckets_traffic_over_time() {
+        let store = store();
+
+        let hourly = store.time_series(&HistoryQuery { bucket: Some(Bucket::Hour), ..range(START, START + 7200) }).unwrap();
+        assert_eq!(hourly, vec![
+            TrafficBucket { start: START, bytes_uploaded: 5250, bytes_downloaded: 1050 },
+            TrafficBucket { start: START + 3600, bytes_uploaded: 1, bytes_downloaded: 1 },
+        ]);
+
+        let per_minute = store.time_series(&HistoryQuery { bucket: Some(Bucket::Minute), ..range(START, START + 3600) }).unwrap();
+        assert_eq!(per_minute.len(), 2);
+        assert_eq!(per_minute[1].start, START + 60);
+    }
+}
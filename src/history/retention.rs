@@ -0,0 +1,304 @@
+use rusqlite::{params, Transaction};
+
+use crate::history::store::HistoryStore;
+
+pub const HOUR: u64 = 60 * 60;
+pub const DAY: u64 = 24 * HOUR;
+
+// How long each resolution is kept before it only survives in the next coarser one; daily rollups are kept forever
+#[derive(Clone, Copy, Debug)]
+pub struct RetentionPolicy {
+    pub raw: u64,
+    pub hourly: u64,
+    // How long after it was taken a sample may still be committed by the history thread
+    pub write_delay: u64,
+}
+
+// Where each resolution starts, so queries read every period from exactly one of them
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Coverage {
+    pub raw_from: u64,
+    pub hourly_from: u64,
+}
+
+impl HistoryStore {
+    // Rolls raw samples up into hourly aggregates and those into daily ones, then drops what fell out of retention.
+    // Only hours and days that ended at least one write delay ago are rolled up, and nothing is deleted before it has
+    // been rolled up. Hourly rollups are kept for as long as the raw samples after them, so no period is left to both.
+    pub fn maintain(&mut self, now: u64, policy: &RetentionPolicy) -> rusqlite::Result<()> {
+        let transaction = self.database.transaction()?;
+
+        let settled = now.saturating_sub(policy.write_delay);
+        let hours_end = settled / HOUR * HOUR;
+        let days_end = settled / DAY * DAY;
+
+        let hourly_rolled_up_to = rolled_up_to(&transaction, "hourly")?;
+        if hours_end > hourly_rolled_up_to {
+            roll_up_raw(&transaction, hourly_rolled_up_to, hours_end)?;
+            set_rolled_up_to(&transaction, "hourly", hours_end)?;
+        }
+
+        let daily_rolled_up_to = rolled_up_to(&transaction, "daily")?;
+        if days_end > daily_rolled_up_to {
+            roll_up_hourly(&transaction, daily_rolled_up_to, days_end)?;
+            set_rolled_up_to(&transaction, "daily", days_end)?;
+        }
+
+        let raw_cutoff = (now.saturating_sub(policy.raw) / HOUR * HOUR).min(hours_end);
+        if raw_cutoff > retained_from(&transaction, "raw")? {
+            transaction.execute("DELETE FROM connection_samples WHERE sampled_at < ?1", params![raw_cutoff])?;
+            transaction.execute("DELETE FROM process_samples WHERE sampled_at < ?1", params![raw_cutoff])?;
+            set_retained_from(&transaction, "raw", raw_cutoff)?;
+        }
+
+        let hourly_cutoff = (now.saturating_sub(policy.hourly) / DAY * DAY).min(days_end).min(raw_cutoff / DAY * DAY);
+        if hourly_cutoff > retained_from(&transaction, "hourly")? {
+            transaction.execute("DELETE FROM connection_rollups WHERE resolution = ?1 AND bucket_start < ?2", params![HOUR, hourly_cutoff])?;
+            transaction.execute("DELETE FROM process_rollups WHERE resolution = ?1 AND bucket_start < ?2", params![HOUR, hourly_cutoff])?;
+            set_retained_from(&transaction, "hourly", hourly_cutoff)?;
+        }
+
+        transaction.commit()
+    }
+
+    pub(super) fn coverage(&self) -> rusqlite::Result<Coverage> {
+        self.database.query_row(
+            "SELECT
+                (SELECT retained_from FROM retention_state WHERE tier = 'raw'),
+                (SELECT retained_from FROM retention_state WHERE tier = 'hourly')",
+            [],
+            |row| Ok(Coverage { raw_from: row.get(0)?, hourly_from: row.get(1)? }),
+        )
+    }
+}
+
+fn roll_up_raw(transaction: &Transaction, from: u64, to: u64) -> rusqlite::Result<()> {
+    transaction.execute(
+        "INSERT INTO connection_rollups (
+            resolution, bucket_start, transport_type, local_address, remote_address, process_id, executable,
+            bytes_uploaded, bytes_downloaded, packets_uploaded, packets_downloaded
+        )
+        SELECT ?3, sampled_at / ?3 * ?3, transport_type, local_address, remote_address, process_id, executable,
+            SUM(bytes_uploaded), SUM(bytes_downloaded), SUM(packets_uploaded), SUM(packets_downloaded)
+        FROM connection_samples
+        WHERE sampled_at >= ?1 AND sampled_at < ?2
+        GROUP BY 2, transport_type, local_address, remote_address, process_id, executable
+        ON CONFLICT DO UPDATE SET
+            bytes_uploaded = bytes_uploaded + excluded.bytes_uploaded,
+            bytes_downloaded = bytes_downloaded + excluded.bytes_downloaded,
+            packets_uploaded = packets_uploaded + excluded.packets_uploaded,
+            packets_downloaded = packets_downloaded + excluded.packets_downloaded",
+        params![from, to, HOUR],
+    )?;
+
+    transaction.execute(
+        "INSERT INTO process_rollups (
+            resolution, bucket_start, process_id, executable, command, bytes_uploaded, bytes_downloaded
+        )
+        SELECT ?3, sampled_at / ?3 * ?3, process_id, executable, MAX(command), SUM(bytes_uploaded), SUM(bytes_downloaded)
+        FROM process_samples
+        WHERE sampled_at >= ?1 AND sampled_at < ?2
+        GROUP BY 2, process_id, executable
+        ON CONFLICT DO UPDATE SET
+            bytes_uploaded = bytes_uploaded + excluded.bytes_uploaded,
+            bytes_downloaded = bytes_downloaded + excluded.bytes_downloaded",
+        params![from, to, HOUR],
+    )?;
+
+    Ok(())
+}
+
+fn roll_up_hourly(transaction: &Transaction, from: u64, to: u64) -> rusqlite::Result<()> {
+    transaction.execute(
+        "INSERT INTO connection_rollups (
+            resolution, bucket_start, transport_type, local_address, remote_address, process_id, executable,
+            bytes_uploaded, bytes_downloaded, packets_uploaded, packets_downloaded
+        )
+        SELECT ?4, bucket_start / ?4 * ?4, transport_type, local_address, remote_address, process_id, executable,
+            SUM(bytes_uploaded), SUM(bytes_downloaded), SUM(packets_uploaded), SUM(packets_downloaded)
+        FROM connection_rollups
+        WHERE resolution = ?3 AND bucket_start >= ?1 AND bucket_start < ?2
+        GROUP BY 2, transport_type, local_address, remote_address, process_id, executable
+        ON CONFLICT DO UPDATE SET
+            bytes_uploaded = bytes_uploaded + excluded.bytes_uploaded,
+            bytes_downloaded = bytes_downloaded + excluded.bytes_downloaded,
+            packets_uploaded = packets_uploaded + excluded.packets_uploaded,
+            packets_downloaded = packets_downloaded + excluded.packets_downloaded",
+        params![from, to, HOUR, DAY],
+    )?;
+
+    transaction.execute(
+        "INSERT INTO process_rollups (
+            resolution, bucket_start, process_id, executable, command, bytes_uploaded, bytes_downloaded
+        )
+        SELECT ?4, bucket_start / ?4 * ?4, process_id, executable, MAX(command), SUM(bytes_uploaded), SUM(bytes_downloaded)
+        FROM process_rollups
+        WHERE resolution = ?3 AND bucket_start >= ?1 AND bucket_start < ?2
+        GROUP BY 2, process_id, executable
+        ON CONFLICT DO UPDATE SET
+            bytes_uploaded = bytes_uploaded + excluded.bytes_uploaded,
+            bytes_downloaded = bytes_downloaded + excluded.bytes_downloaded",
+        params![from, to, HOUR, DAY],
+    )?;
+
+    Ok(())
+}
+
+fn rolled_up_to(transaction: &Transaction, tier: &str) -> rusqlite::Result<u64> {
+    transaction.query_row("SELECT rolled_up_to FROM retention_state WHERE tier = ?1", params![tier], |row| row.get(0))
+}
+
+fn set_rolled_up_to(transaction: &Transaction, tier: &str, rolled_up_to: u64) -> rusqlite::Result<()> {
+    transaction.execute("UPDATE retention_state SET rolled_up_to = ?2 WHERE tier = ?1", params![tier, rolled_up_to])?;
+    Ok(())
+}
+
+fn retained_from(transaction: &Transaction, tier: &str) -> rusqlite::Result<u64> {
+    transaction.query_row("SELECT retained_from FROM retention_state WHERE tier = ?1", params![tier], |row| row.get(0))
+}
+
+fn set_retained_from(transaction: &Transaction, tier: &str, retained_from: u64) -> rusqlite::Result<()> {
+    transaction.execute("UPDATE retention_state SET retained_from = ?2 WHERE tier = ?1", params![tier, retained_from])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::{Coverage, RetentionPolicy, DAY, HOUR};
+    use crate::history::queries::{Bucket, HistoryQuery, TrafficBucket};
+    use crate::history::samples::{ConnectionSample, ProcessSample};
+    use crate::history::store::HistoryStore;
+    use crate::structs::connection::TransportType;
+
+    const START: u64 = 1_700_000_000 / DAY * DAY;
+    const POLICY: RetentionPolicy = RetentionPolicy { raw: 2 * HOUR, hourly: 2 * DAY, write_delay: 0 };
+
+    fn record(store: &mut HistoryStore, sampled_at: u64, bytes_uploaded: usize) {
+        let connection = ConnectionSample {
+            transport_type: TransportType::Tcp,
+            local_address: SocketAddr::from(([10, 0, 0, 1], 40000)),
+            remote_address: SocketAddr::from(([1, 1, 1, 1], 443)),
+            process_id: 42,
+            executable: "/usr/bin/curl".to_string(),
+            bytes_uploaded,
+            bytes_downloaded: 0,
+            packets_uploaded: 1,
+            packets_downloaded: 0,
+        };
+        let process = ProcessSample {
+            process_id: 42,
+            executable: "/usr/bin/curl".to_string(),
+            command: "curl example.com".to_string(),
+            bytes_uploaded,
+            bytes_downloaded: 0,
+        };
+
+        store.record(sampled_at, &[connection], &[process]).unwrap();
+    }
+
+    fn count(store: &HistoryStore, sql: &str) -> u64 {
+        store.database.query_row(sql, [], |row| row.get(0)).unwrap()
+    }
+
+    fn everything() -> HistoryQuery {
+        HistoryQuery { from: Some(0), to: Some(START + 10 * DAY), ..HistoryQuery::default() }
+    }
+
+    #[test]
+    fn rolls_up_complete_hours_and_days() {
+        let mut store = HistoryStore::open_in_memory().unwrap();
+        record(&mut store, START + 60, 10);
+        record(&mut store, START + 120, 20);
+        record(&mut store, START + HOUR + 60, 30);
+        record(&mut store, START + DAY + 60, 40);
+
+        store.maintain(START + DAY + 120, &POLICY).unwrap();
+
+        assert_eq!(count(&store, "SELECT COUNT(*) FROM process_rollups WHERE resolution = 3600"), 2);
+        assert_eq!(count(&store, "SELECT bytes_uploaded FROM process_rollups WHERE resolution = 3600 ORDER BY bucket_start"), 30);
+        assert_eq!(count(&store, "SELECT COUNT(*) FROM connection_rollups WHERE resolution = 86400"), 1);
+        assert_eq!(count(&store, "SELECT packets_uploaded FROM connection_rollups WHERE resolution = 86400"), 3);
+    }
+
+    #[test]
+    fn running_twice_does_not_double_count() {
+        let mut store = HistoryStore::open_in_memory().unwrap();
+        record(&mut store, START + 60, 10);
+
+        store.maintain(START + HOUR, &POLICY).unwrap();
+        store.maintain(START + HOUR + 60, &POLICY).unwrap();
+        record(&mut store, START + HOUR + 120, 5);
+        store.maintain(START + 2 * HOUR, &POLICY).unwrap();
+
+        assert_eq!(count(&store, "SELECT SUM(bytes_uploaded) FROM process_rollups WHERE resolution = 3600"), 15);
+    }
+
+    #[test]
+    fn waits_for_samples_still_being_written() {
+        let mut store = HistoryStore::open_in_memory().unwrap();
+        let policy = RetentionPolicy { write_delay: 60, ..POLICY };
+
+        // Taken just before the hour ended but committed after the next maintenance started
+        store.maintain(START + HOUR + 10, &policy).unwrap();
+        record(&mut store, START + HOUR - 30, 10);
+        store.maintain(START + HOUR + 60, &policy).unwrap();
+
+        assert_eq!(count(&store, "SELECT SUM(bytes_uploaded) FROM process_rollups WHERE resolution = 3600"), 10);
+    }
+
+    #[test]
+    fn keeps_hourly_rollups_while_raw_samples_are_kept() {
+        let mut store = HistoryStore::open_in_memory().unwrap();
+        for hour in 0..(3 * 24) {
+            record(&mut store, START + hour * HOUR + 60, 1);
+        }
+        let before = store.top_processes(&everything()).unwrap();
+
+        let now = START + 3 * DAY;
+        store.maintain(now, &RetentionPolicy { raw: 60 * HOUR, hourly: DAY, write_delay: 0 }).unwrap();
+
+        assert_eq!(store.coverage().unwrap(), Coverage { raw_from: now - 60 * HOUR, hourly_from: START });
+        assert_eq!(store.top_processes(&everything()).unwrap(), before);
+    }
+
+    #[test]
+    fn deletes_rows_outside_retention() {
+        let mut store = HistoryStore::open_in_memory().unwrap();
+        for hour in 0..(3 * 24) {
+            record(&mut store, START + hour * HOUR + 60, 1);
+        }
+
+        let now = START + 3 * DAY;
+        store.maintain(now, &POLICY).unwrap();
+
+        assert_eq!(count(&store, "SELECT MIN(sampled_at) FROM process_samples"), now - 2 * HOUR + 60);
+        assert_eq!(count(&store, "SELECT MIN(sampled_at) FROM connection_samples"), now - 2 * HOUR + 60);
+        assert_eq!(count(&store, "SELECT MIN(bucket_start) FROM process_rollups WHERE resolution = 3600"), START + DAY);
+        assert_eq!(count(&store, "SELECT COUNT(*) FROM process_rollups WHERE resolution = 86400"), 3);
+        assert_eq!(store.coverage().unwrap(), Coverage { raw_from: now - 2 * HOUR, hourly_from: START + DAY });
+    }
+
+    #[test]
+    fn queries_read_each_period_from_one_resolution() {
+        let mut store = HistoryStore::open_in_memory().unwrap();
+        for hour in 0..(3 * 24) {
+            record(&mut store, START + hour * HOUR + 60, 1);
+        }
+        let before = store.top_processes(&everything()).unwrap();
+
+        store.maintain(START + 3 * DAY, &POLICY).unwrap();
+
+        assert_eq!(store.top_processes(&everything()).unwrap(), before);
+        assert_eq!(store.connection_totals(&everything()).unwrap()[0].packets_uploaded, 72);
+        assert_eq!(store.top_hosts(&everything()).unwrap()[0].bytes_uploaded, 72);
+
+        let daily = store.time_series(&HistoryQuery { bucket: Some(Bucket::Day), ..everything() }).unwrap();
+        assert_eq!(daily, vec![
+            TrafficBucket { start: START, bytes_uploaded: 24, bytes_downloaded: 0 },
+            TrafficBucket { start: START + DAY, bytes_uploaded: 24, bytes_downloaded: 0 },
+            TrafficBucket { start: START + 2 * DAY, bytes_uploaded: 24, bytes_downloaded: 0 },
+        ]);
+    }
+}
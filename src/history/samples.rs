@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use libc::pid_t;
+
+use crate::structs::connection::{Connection, Connections, TransportType};
+use crate::structs::process::{ProcessInfo, ProcessKey};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConnectionSample {
+    pub transport_type: TransportType,
+    pub local_address: SocketAddr,
+    pub remote_address: SocketAddr,
+    pub process_id: pid_t,
+    pub executable: String,
+    pub bytes_uploaded: usize,
+    pub bytes_downloaded: usize,
+    pub packets_uploaded: usize,
+    pub packets_downloaded: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProcessSample {
+    pub process_id: pid_t,
+    pub executable: String,
+    pub command: String,
+    pub bytes_uploaded: usize,
+    pub bytes_downloaded: usize,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Counters {
+    bytes_uploaded: usize,
+    bytes_downloaded: usize,
+    packets_uploaded: usize,
+    packets_downloaded: usize,
+}
+
+impl From<&Connection> for Counters {
+    fn from(connection: &Connection) -> Self {
+        Counters {
+            bytes_uploaded: connection.bytes_uploaded,
+            bytes_downloaded: connection.bytes_downloaded,
+            packets_uploaded: connection.packets_uploaded,
+            packets_downloaded: connection.packets_downloaded,
+        }
+    }
+}
+
+// Turns the cumulative counters on connections into the traffic seen since the previous sample. Keyed by id, so a flow
+// that was evicted and seen again starts counting from zero.
+#[derive(Default)]
+pub struct SampleTracker {
+    previous: HashMap<String, Counters>,
+}
+
+impl SampleTracker {
+    pub fn sample<'a, F>(&mut self, connections: &Connections, process_of: F) -> (Vec<ConnectionSample>, Vec<ProcessSample>)
+        where F: Fn(&Connection) -> Option<&'a ProcessInfo> {
+        let mut connection_samples = Vec::new();
+        // By process rather than pid, so a process that reused the pid of an exited one gets its own row
+        let mut process_samples: HashMap<ProcessKey, ProcessSample> = HashMap::new();
+        let mut current = HashMap::with_capacity(connections.len());
+
+        for connection in connections {
+            let counters = Counters::from(connection);
+            let previous = self.previous.get(&connection.id).copied().unwrap_or_default();
+            current.insert(connection.id.clone(), counters);
+
+            let bytes_uploaded = counters.bytes_uploaded.saturating_sub(previous.bytes_uploaded);
+            let bytes_downloaded = counters.bytes_downloaded.saturating_sub(previous.bytes_downloaded);
+            if bytes_uploaded == 0 && bytes_downloaded == 0 {
+                continue;
+            }
+
+            let process = process_of(connection);
+            let executable = process.map(|process| process.executable.clone()).unwrap_or_default();
+
+            connection_samples.push(ConnectionSample {
+                transport_type: connection.transport_type.clone(),
+                local_address: connection.source,
+                remote_address: connection.destination,
+                process_id: connection.process_id,
+                executable: executable.clone(),
+                bytes_uploaded,
+                bytes_downloaded,
+                packets_uploaded: counters.packets_uploaded.saturating_sub(previous.packets_uploaded),
+                packets_downloaded: counters.packets_downloaded.saturating_sub(previous.packets_downloaded),
+            });
+
+            let process_sample = process_samples.entry(connection.process_key()).or_insert_with(|| ProcessSample {
+                process_id: connection.process_id,
+                executable,
+                command: process.map(|process| process.command.clone()).unwrap_or_default(),
+                bytes_uploaded: 0,
+                bytes_downloaded: 0,
+            });
+            process_sample.bytes_uploaded += bytes_uploaded;
+            process_sample.bytes_downloaded += bytes_downloaded;
+        }
+
+        self.previous = current;
+
+        (connection_samples, process_samples.into_values().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::SampleTracker;
+    use crate::helpers::testing;
+    use crate::structs::connection::Connection;
+    use crate::structs::process::{ProcessInfo, ProcessInfos};
+
+    fn connection(port: u16, bytes_uploaded: usize, bytes_downloaded: usize) -> Connection {
+        Connection { process_id: 42, bytes_uploaded, bytes_downloaded, ..testing::connection(port) }
+    }
+
+    fn processes() -> ProcessInfos {
+        testing::by_pid(vec![testing::process(42, "/usr/bin/curl")])
+    }
+
+    #[test]
+    fn samples_traffic_since_previous_sample() {
+        let mut tracker = SampleTracker::default();
+        let processes = processes();
+
+        let (first, _) = tracker.sample(&vec![connection(1000, 100, 200)], |connection| processes.get(&connection.process_id));
+        let (second, second_processes) = tracker.sample(&vec![connection(1000, 150, 200), connection(1001, 10, 0)], |connection| processes.get(&connection.process_id));
+
+        assert_eq!(first[0].bytes_uploaded, 100);
+        assert_eq!(first[0].bytes_downloaded, 200);
+        assert_eq!(first[0].executable, "/usr/bin/curl");
+
+        assert_eq!(second.len(), 2);
+        assert_eq!(second.iter().map(|sample| sample.bytes_uploaded).sum::<usize>(), 60);
+        assert_eq!(second_processes.len(), 1);
+        assert_eq!(second_processes[0].bytes_uploaded, 60);
+        assert_eq!(second_processes[0].bytes_downloaded, 0);
+    }
+
+    #[test]
+    fn samples_processes_sharing_a_pid_separately() {
+        let mut tracker = SampleTracker::default();
+        let exited = ProcessInfo { start_time: 50, executable: "/usr/bin/wget".to_string(), ..processes()[&42].clone() };
+        let processes = processes();
+        let connections = vec![connection(1000, 100, 0), Connection { process_start_time: 50, ..connection(1001, 10, 0) }];
+
+        let (_, mut process_samples) = tracker.sample(&connections, |connection| match connection.process_start_time {
+            50 => Some(&exited),
+            _ => processes.get(&connection.process_id),
+        });
+        process_samples.sort_by_key(|sample| sample.bytes_uploaded);
+
+        let executables: Vec<(&str, usize)> = process_samples.iter()
+            .map(|sample| (sample.executable.as_str(), sample.bytes_uploaded))
+            .collect();
+        assert_eq!(executables, vec![("/usr/bin/wget", 10), ("/usr/bin/curl", 100)]);
+    }
+
+    #[test]
+    fn samples_flows_seen_again_from_zero() {
+        let mut tracker = SampleTracker::default();
+        let processes = processes();
+        let first = connection(1000, 100, 200);
+        // The same addresses and ports, after the first flow was evicted
+        let mut again = Connection { first_seen: UNIX_EPOCH + Duration::from_secs(300), ..connection(1000, 150, 250) };
+        again.identify();
+
+        tracker.sample(&vec![first], |connection| processes.get(&connection.process_id));
+        let (samples, _) = tracker.sample(&vec![again], |connection| processes.get(&connection.process_id));
+
+        assert_eq!((samples[0].bytes_uploaded, samples[0].bytes_downloaded), (150, 250));
+    }
+
+    #[test]
+    fn skips_idle_connections() {
+        let mut tracker = SampleTracker::default();
+        let processes = processes();
+
+        tracker.sample(&vec![connection(1000, 100, 200)], |connection| processes.get(&connection.process_id));
+        let (samples, process_samples) = tracker.sample(&vec![connection(1000, 100, 200)], |connection| processes.get(&connection.process_id));
+
+        assert!(samples.is_empty());
+        assert!(process_samples.is_empty());
+    }
+}
@@ -0,0 +1,121 @@
+use std::path::Path;
+
+use rusqlite::{params, Connection};
+
+use crate::history::migrations::migrate;
+use crate::history::samples::{ConnectionSample, ProcessSample};
+
+pub struct HistoryStore {
+    pub(super) database: Connection,
+}
+
+impl HistoryStore {
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let database = Connection::open(path)?;
+        database.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+
+        HistoryStore::from_connection(database)
+    }
+
+    #[cfg(test)]
+    pub fn open_in_memory() -> rusqlite::Result<Self> {
+        HistoryStore::from_connection(Connection::open_in_memory()?)
+    }
+
+    fn from_connection(mut database: Connection) -> rusqlite::Result<Self> {
+        migrate(&mut database)?;
+
+        Ok(HistoryStore { database })
+    }
+
+    pub fn record(&mut self, sampled_at: u64, connections: &[ConnectionSample], processes: &[ProcessSample]) -> rusqlite::Result<()> {
+        let transaction = self.database.transaction()?;
+
+        {
+            let mut insert_connection = transaction.prepare_cached(
+                "INSERT INTO connection_samples (
+                    sampled_at, transport_type, local_address, remote_address, process_id, executable,
+                    bytes_uploaded, bytes_downloaded, packets_uploaded, packets_downloaded
+                ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)"
+            )?;
+            for sample in connections {
+                insert_connection.execute(params![
+                    sampled_at,
+                    format!("{:?}", sample.transport_type),
+                    sample.local_address.to_string(),
+                    sample.remote_address.to_string(),
+                    sample.process_id,
+                    sample.executable,
+                    sample.bytes_uploaded,
+                    sample.bytes_downloaded,
+                    sample.packets_uploaded,
+                    sample.packets_downloaded,
+                ])?;
+            }
+
+            let mut insert_process = transaction.prepare_cached(
+                "INSERT INTO process_samples (
+                    sampled_at, process_id, executable, command, bytes_uploaded, bytes_downloaded
+                ) VALUES (?1, ?2, ?3, ?4, ?5, ?6)"
+            )?;
+            for sample in processes {
+                insert_process.execute(params![
+                    sampled_at,
+                    sample.process_id,
+                    sample.executable,
+                    sample.command,
+                    sample.bytes_uploaded,
+                    sample.bytes_downloaded,
+                ])?;
+            }
+        }
+
+        transaction.commit()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::HistoryStore;
+    use crate::history::samples::{ConnectionSample, ProcessSample};
+    use crate::structs::connection::TransportType;
+
+    #[test]
+    fn records_connection_and_process_samples() {
+        let mut store = HistoryStore::open_in_memory().unwrap();
+        let connection = ConnectionSample {
+            transport_type: TransportType::Udp,
+            local_address: SocketAddr::from(([10, 0, 0, 1], 5353)),
+            remote_address: SocketAddr::from(([1, 1, 1, 1], 53)),
+            process_id: 42,
+            executable: "/usr/bin/dig".to_string(),
+            bytes_uploaded: 60,
+            bytes_downloaded: 120,
+            packets_uploaded: 1,
+            packets_downloaded: 1,
+        };
+        let process = ProcessSample {
+            process_id: 42,
+            executable: "/usr/bin/dig".to_string(),
+            command: "dig example.com".to_string(),
+            bytes_uploaded: 60,
+            bytes_downloaded: 120,
+        };
+
+        store.record(1_700_000_000, &[connection], &[process]).unwrap();
+
+        let (transport_type, remote_address, bytes_downloaded): (String, String, i64) = store.database.query_row(
+            "SELECT transport_type, remote_address, bytes_downloaded FROM connection_samples",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        ).unwrap();
+        assert_eq!(transport_type, "Udp");
+        assert_eq!(remote_address, "1.1.1.1:53");
+        assert_eq!(bytes_downloaded, 120);
+
+        let command: String = store.database.query_row("SELECT command FROM process_samples", [], |row| row.get(0)).unwrap();
+        assert_eq!(command, "dig example.com");
+    }
+}
@@ -1,62 +1,148 @@
 use std::env;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
 use actix_cors::Cors;
 use actix_web::{App, rt, get, HttpResponse, HttpServer, middleware, web};
-use serde_json::json;
+use serde::Serialize;
 
-use crate::structs::connection::Connections;
-use crate::structs::receivers::{CaptureReceiver, ProcessesReceiver};
-use crate::structs::process::ProcessInfos;
+use crate::api::{DeltaQuery, ErrorResponse, Snapshot};
+use crate::history::queries::{ConnectionTraffic, HistoryQuery, HostTraffic, ProcessTraffic, TrafficBucket};
+use crate::history::store::HistoryStore;
+use crate::structs::config::Config;
+use crate::structs::connection::ConnectionSort;
+use crate::structs::health::{CaptureHealth, ScanHealth};
+use crate::structs::state::State;
+use crate::threads::capture::CaptureSource;
 
 mod structs;
 mod threads;
 mod helpers;
+mod history;
+mod api;
 
+#[utoipa::path(
+    params(ConnectionSort, DeltaQuery),
+    responses((status = 200, body = Snapshot)),
+)]
 #[get("/")]
-async fn index(state: web::Data<Mutex<(CaptureReceiver, ProcessesReceiver, Connections, ProcessInfos)>>) -> HttpResponse {
-    let mut locked_state = state.lock().unwrap();
-
-    let (ref mut receiver, ref mut processes_receiver, ref mut connections, ref mut processes) = *locked_state;
+async fn index(state: web::Data<Mutex<State>>, sort: web::Query<ConnectionSort>, delta: web::Query<DeltaQuery>) -> HttpResponse {
+    HttpResponse::Ok().json(api::snapshot(&mut state.lock().unwrap(), sort.into_inner(), delta.since))
+}
 
-    if let Some(latest_connections) = receiver.latest() {
-        *connections = latest_connections.clone()
-    }
+#[utoipa::path(
+    params(HistoryQuery),
+    responses((status = 200, body = Vec<ProcessTraffic>), (status = 500, body = ErrorResponse)),
+)]
+#[get("/history/processes")]
+async fn history_processes(history: web::Data<Mutex<HistoryStore>>, query: web::Query<HistoryQuery>) -> HttpResponse {
+    history_response(history.lock().unwrap().top_processes(&query))
+}
 
-    if let Some(latest_processes) = processes_receiver.latest() {
-        processes.extend(latest_processes.clone());
-    }
+#[utoipa::path(
+    params(HistoryQuery),
+    responses((status = 200, body = Vec<HostTraffic>), (status = 500, body = ErrorResponse)),
+)]
+#[get("/history/hosts")]
+async fn history_hosts(history: web::Data<Mutex<HistoryStore>>, query: web::Query<HistoryQuery>) -> HttpResponse {
+    history_response(history.lock().unwrap().top_hosts(&query))
+}
 
-    for connection in connections.iter_mut() {
-        connection.bind_matching_process(processes);
-    }
+#[utoipa::path(
+    params(HistoryQuery),
+    responses((status = 200, body = Vec<ConnectionTraffic>), (status = 500, body = ErrorResponse)),
+)]
+#[get("/history/connections")]
+async fn history_connections(history: web::Data<Mutex<HistoryStore>>, query: web::Query<HistoryQuery>) -> HttpResponse {
+    history_response(history.lock().unwrap().connection_totals(&query))
+}
 
-    let filtered_processes: ProcessInfos = processes.iter()
-        .filter(|&(_, process)| !process.executable.is_empty())
-        .map(|(pid, process)| (*pid, process.clone()))
-        .collect();
+#[utoipa::path(
+    params(HistoryQuery),
+    responses((status = 200, body = Vec<TrafficBucket>), (status = 500, body = ErrorResponse)),
+)]
+#[get("/history/series")]
+async fn history_series(history: web::Data<Mutex<HistoryStore>>, query: web::Query<HistoryQuery>) -> HttpResponse {
+    history_response(history.lock().unwrap().time_series(&query))
+}
 
-    HttpResponse::Ok().json(json!({"connections": connections, "processes": filtered_processes}))
+fn history_response<T: Serialize>(result: rusqlite::Result<T>) -> HttpResponse {
+    match result {
+        Ok(rows) => HttpResponse::Ok().json(rows),
+        Err(error) => HttpResponse::InternalServerError().json(ErrorResponse { error: error.to_string() }),
+    }
 }
 
 fn main() -> std::io::Result<()> {
-    let device_name = env::args().nth(1);
+    let config = match Config::from_args(env::args().skip(1)) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("Error: {}", error);
+            std::process::exit(1);
+        }
+    };
+
+    let scans = Arc::new(ScanHealth::default());
+    let health = Arc::new(CaptureHealth::default());
+    // A replayed capture was recorded elsewhere or earlier, so neither the sockets nor the processes of this host apply
+    let (capture_thread, processes_thread) = match &config.capture_file {
+        Some(capture_file) => {
+            let capture = threads::capture::open_file(capture_file, &config.filter).unwrap_or_else(|error| {
+                eprintln!("Error: {}", error);
+                std::process::exit(1);
+            });
+            let (_, capture_thread) = threads::capture::run(200, CaptureSource::File(capture), &config, health.clone());
+            let (processes_thread, _) = single_value_channel::channel();
+            (capture_thread, processes_thread)
+        }
+        None => {
+            let (_, connections_thread) = threads::connections::run(200);
+            let (_, processes_thread) = threads::processes::run(200, scans.clone());
+            let (_, capture_thread) = threads::capture::run(200, CaptureSource::Devices(connections_thread), &config, health.clone());
+            (capture_thread, processes_thread)
+        }
+    };
 
-    let (_, connections_thread) = threads::connections::run(200);
-    let (_, processes_thread) = threads::processes::run(200);
-    let (_, capture_thread) = threads::capture::run(connections_thread, &device_name);
+    let shared_state = Arc::new(Mutex::new(State::new(capture_thread, processes_thread)));
+    let state = web::Data::from(shared_state.clone());
+    let health = web::Data::from(health);
+    let scans = web::Data::from(scans);
+    let app_config = web::Data::new(config.clone());
 
+    let history = config.database.as_ref().map(|database| {
+        let open_store = || HistoryStore::open(database).unwrap_or_else(|error| {
+            eprintln!("Error: Failed to open history database {}: {}", database.display(), error);
+            std::process::exit(1);
+        });
+        threads::history::run(config.history_interval * 1000, shared_state.clone(), open_store());
+        threads::maintenance::run(5 * 60 * 1000, open_store(), config.retention_policy());
+        println!("Recording history to {}", database.display());
 
-    let state = web::Data::new(Mutex::new((capture_thread, processes_thread, Connections::new(), ProcessInfos::new())));
+        web::Data::new(Mutex::new(open_store()))
+    });
 
     let host = env::var("HOST").unwrap_or("127.0.0.1:8080".to_string());
     println!("Starting server at {}...", host);
     rt::System::new().block_on(HttpServer::new(move || {
         App::new()
             .app_data(state.clone())
+            .app_data(health.clone())
+            .app_data(scans.clone())
+            .app_data(app_config.clone())
             .wrap(middleware::Logger::default())
             .wrap(Cors::permissive().allowed_methods(vec!["GET"]).max_age(3600))
             .service(index)
+            .service(api::events::events)
+            .service(api::websocket::websocket)
+            .service(api::metrics::metrics)
+            .configure(api::v1::configure)
+            .configure(|service_config| if let Some(history) = &history {
+                service_config
+                    .app_data(history.clone())
+                    .service(history_processes)
+                    .service(history_hosts)
+                    .service(history_connections)
+                    .service(history_series);
+            })
     })
         .bind(host)?
         .run()
@@ -0,0 +1,356 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+
+use libc::pid_t;
+use serde_derive::Serialize;
+use utoipa::ToSchema;
+
+use crate::structs::cgroup::{Container, ContainerRuntime};
+use crate::structs::connection::{Connection, Connections};
+use crate::structs::process::{ProcessInfo, ProcessKey};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, ToSchema)]
+pub struct TrafficTotals {
+    pub bytes_uploaded: usize,
+    pub bytes_downloaded: usize,
+    pub packets_uploaded: usize,
+    pub packets_downloaded: usize,
+    pub connections: usize,
+}
+
+impl TrafficTotals {
+    pub fn add(&mut self, connection: &Connection) {
+        self.bytes_uploaded += connection.bytes_uploaded;
+        self.bytes_downloaded += connection.bytes_downloaded;
+        self.packets_uploaded += connection.packets_uploaded;
+        self.packets_downloaded += connection.packets_downloaded;
+        self.connections += 1;
+    }
+
+    pub fn merge(&mut self, other: &TrafficTotals) {
+        self.bytes_uploaded += other.bytes_uploaded;
+        self.bytes_downloaded += other.bytes_downloaded;
+        self.packets_uploaded += other.packets_uploaded;
+        self.packets_downloaded += other.packets_downloaded;
+        self.connections += other.connections;
+    }
+
+    pub fn bytes_total(&self) -> usize {
+        self.bytes_uploaded + self.bytes_downloaded
+    }
+
+    // What was added on top of earlier totals of the same connections
+    pub fn since(&self, previous: &TrafficTotals) -> TrafficTotals {
+        TrafficTotals {
+            bytes_uploaded: self.bytes_uploaded.saturating_sub(previous.bytes_uploaded),
+            bytes_downloaded: self.bytes_downloaded.saturating_sub(previous.bytes_downloaded),
+            packets_uploaded: self.packets_uploaded.saturating_sub(previous.packets_uploaded),
+            packets_downloaded: self.packets_downloaded.saturating_sub(previous.packets_downloaded),
+            connections: self.connections.saturating_sub(previous.connections),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, ToSchema)]
+pub struct ProcessTotals {
+    #[schema(value_type = i32)]
+    pub pid: pid_t,
+    pub start_time: u64,
+    pub exited: bool,
+    pub name: String,
+    pub command: String,
+    pub executable: String,
+    pub uid: u32,
+    pub user: Option<String>,
+    #[serde(flatten)]
+    pub traffic: TrafficTotals,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, ToSchema)]
+pub struct UserTotals {
+    pub uid: u32,
+    pub user: Option<String>,
+    #[schema(value_type = Vec<i32>)]
+    pub pids: Vec<pid_t>,
+    #[serde(flatten)]
+    pub traffic: TrafficTotals,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, ToSchema)]
+pub struct ExecutableTotals {
+    pub executable: String,
+    #[schema(value_type = Vec<i32>)]
+    pub pids: Vec<pid_t>,
+    #[serde(flatten)]
+    pub traffic: TrafficTotals,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, ToSchema)]
+pub struct ContainerTotals {
+    pub runtime: ContainerRuntime,
+    pub id: String,
+    pub pod: Option<String>,
+    #[schema(value_type = Vec<i32>)]
+    pub pids: Vec<pid_t>,
+    #[serde(flatten)]
+    pub traffic: TrafficTotals,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, ToSchema)]
+pub struct UnitTotals {
+    pub unit: String,
+    pub slice: Option<String>,
+    #[schema(value_type = Vec<i32>)]
+    pub pids: Vec<pid_t>,
+    #[serde(flatten)]
+    pub traffic: TrafficTotals,
+}
+
+// All lists are ordered by bytes transferred, most first
+#[derive(Clone, Debug, Default, PartialEq, Serialize, ToSchema)]
+pub struct ProcessAggregates {
+    pub processes: Vec<ProcessTotals>,
+    pub executables: Vec<ExecutableTotals>,
+    pub users: Vec<UserTotals>,
+    pub containers: Vec<ContainerTotals>,
+    pub units: Vec<UnitTotals>,
+}
+
+fn add_process(pids: &mut Vec<pid_t>, totals: &mut TrafficTotals, pid: pid_t, traffic: &TrafficTotals) {
+    if !pids.contains(&pid) {
+        pids.push(pid);
+    }
+    totals.merge(traffic);
+}
+
+// Sums the connections attributed to a known process, per process, per executable path across pids and per
+// real user, container and systemd unit. Processes whose executable or cgroup could not be read are left out of
+// the lists keyed by them.
+pub fn aggregate<'a, F>(connections: &Connections, process_of: F) -> ProcessAggregates
+    where F: Fn(&Connection) -> Option<&'a ProcessInfo> {
+    let mut by_process: BTreeMap<ProcessKey, (&ProcessInfo, TrafficTotals)> = BTreeMap::new();
+    for connection in connections {
+        if let Some(process) = process_of(connection) {
+            by_process.entry(process.key()).or_insert((process, TrafficTotals::default())).1.add(connection);
+        }
+    }
+
+    let mut by_executable: BTreeMap<&str, ExecutableTotals> = BTreeMap::new();
+    let mut by_user: BTreeMap<u32, UserTotals> = BTreeMap::new();
+    let mut by_container: BTreeMap<&Container, ContainerTotals> = BTreeMap::new();
+    let mut by_unit: BTreeMap<&str, UnitTotals> = BTreeMap::new();
+    let mut process_totals = Vec::with_capacity(by_process.len());
+    for (key, (process, traffic)) in by_process {
+        if !process.executable.is_empty() {
+            let totals = by_executable.entry(&process.executable).or_insert_with(|| ExecutableTotals {
+                executable: process.executable.clone(),
+                pids: vec![],
+                traffic: TrafficTotals::default(),
+            });
+            add_process(&mut totals.pids, &mut totals.traffic, key.pid, &traffic);
+        }
+
+        let totals = by_user.entry(process.uid).or_insert_with(|| UserTotals {
+            uid: process.uid,
+            user: process.user.clone(),
+            pids: vec![],
+            traffic: TrafficTotals::default(),
+        });
+        add_process(&mut totals.pids, &mut totals.traffic, key.pid, &traffic);
+
+        let cgroup = process.cgroup.as_ref();
+        if let Some(container) = cgroup.and_then(|cgroup| cgroup.container.as_ref()) {
+            let totals = by_container.entry(container).or_insert_with(|| ContainerTotals {
+                runtime: container.runtime,
+                id: container.id.clone(),
+                pod: container.pod.clone(),
+                pids: vec![],
+                traffic: TrafficTotals::default(),
+            });
+            add_process(&mut totals.pids, &mut totals.traffic, key.pid, &traffic);
+        }
+        if let Some((unit, slice)) = cgroup.and_then(|cgroup| Some((cgroup.unit.as_deref()?, &cgroup.slice))) {
+            let totals = by_unit.entry(unit).or_insert_with(|| UnitTotals {
+                unit: unit.to_string(),
+                slice: slice.clone(),
+                pids: vec![],
+                traffic: TrafficTotals::default(),
+            });
+            add_process(&mut totals.pids, &mut totals.traffic, key.pid, &traffic);
+        }
+
+        process_totals.push(ProcessTotals {
+            pid: key.pid,
+            start_time: key.start_time,
+            exited: process.exited,
+            name: process.name.clone(),
+            command: process.command.clone(),
+            executable: process.executable.clone(),
+            uid: process.uid,
+            user: process.user.clone(),
+            traffic,
+        });
+    }
+
+    let mut executable_totals: Vec<ExecutableTotals> = by_executable.into_values().collect();
+    let mut user_totals: Vec<UserTotals> = by_user.into_values().collect();
+    let mut container_totals: Vec<ContainerTotals> = by_container.into_values().collect();
+    let mut unit_totals: Vec<UnitTotals> = by_unit.into_values().collect();
+    process_totals.sort_by_key(|totals| Reverse(totals.traffic.bytes_total()));
+    executable_totals.sort_by_key(|totals| Reverse(totals.traffic.bytes_total()));
+    user_totals.sort_by_key(|totals| Reverse(totals.traffic.bytes_total()));
+    container_totals.sort_by_key(|totals| Reverse(totals.traffic.bytes_total()));
+    unit_totals.sort_by_key(|totals| Reverse(totals.traffic.bytes_total()));
+
+    ProcessAggregates {
+        processes: process_totals,
+        executables: executable_totals,
+        users: user_totals,
+        containers: container_totals,
+        units: unit_totals,
+    }
+}
+
+// Traffic per process since startup, added up from how much its connections grew between refreshes so that it keeps
+// counting after their flows are evicted. Traffic from before a connection was bound stays with the unknown process.
+#[derive(Debug, Default)]
+pub struct ProcessTraffic {
+    previous: HashMap<String, TrafficTotals>,
+    pub totals: BTreeMap<ProcessKey, (String, TrafficTotals)>,
+}
+
+impl ProcessTraffic {
+    pub fn record<'a, F>(&mut self, connections: &Connections, process_of: F)
+        where F: Fn(&Connection) -> Option<&'a ProcessInfo> {
+        let mut current = HashMap::with_capacity(connections.len());
+
+        for connection in connections {
+            let mut traffic = TrafficTotals::default();
+            traffic.add(connection);
+            let added = traffic.since(&self.previous.get(&connection.id).copied().unwrap_or_default());
+            current.insert(connection.id.clone(), traffic);
+
+            // Sockets read from /proc without any packets yet
+            if added.bytes_total() == 0 && added.packets_uploaded + added.packets_downloaded == 0 {
+                continue;
+            }
+            let (executable, totals) = self.totals.entry(connection.process_key()).or_default();
+            if executable.is_empty() {
+                *executable = process_of(connection).map(|process| process.executable.clone()).unwrap_or_default();
+            }
+            totals.merge(&added);
+        }
+
+        self.previous = current;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libc::pid_t;
+
+    use super::{aggregate, TrafficTotals};
+    use crate::helpers::testing;
+    use crate::structs::cgroup::{Cgroup, ContainerRuntime};
+    use crate::structs::connection::Connection;
+    use crate::structs::process::{ProcessInfo, ProcessInfos};
+    use crate::threads::processes::index_inodes;
+
+    fn connection(local_port: u16, inode: u64, bytes_uploaded: usize) -> Connection {
+        Connection {
+            inode,
+            bytes_uploaded,
+            bytes_downloaded: 10,
+            packets_uploaded: 1,
+            packets_downloaded: 2,
+            ..testing::connection(local_port)
+        }
+    }
+
+    fn process(pid: pid_t, executable: &str, inodes: Vec<u64>) -> ProcessInfo {
+        ProcessInfo { inodes, ..testing::process(pid, executable) }
+    }
+
+    #[test]
+    fn sums_bound_connections_per_process_executable_and_user() {
+        let mut processes = ProcessInfos::new();
+        processes.insert(10, process(10, "/usr/bin/curl", vec![1, 2]));
+        processes.insert(11, process(11, "/usr/bin/curl", vec![3]));
+        processes.insert(20, ProcessInfo { uid: 1000, user: Some("alice".to_string()), ..process(20, "/usr/bin/ssh", vec![4]) });
+        processes.insert(30, process(30, "", vec![5]));
+
+        let mut connections = vec![
+            connection(1000, 1, 100),
+            connection(1001, 2, 100),
+            connection(1002, 3, 5),
+            connection(1003, 4, 1000),
+            connection(1004, 5, 1),
+            connection(1005, 99, 50_000),
+        ];
+        let inodes = index_inodes(&processes);
+        for connection in connections.iter_mut() {
+            connection.bind_matching_process(&inodes);
+        }
+
+        let aggregates = aggregate(&connections, |connection| processes.get(&connection.process_id));
+
+        let pids: Vec<pid_t> = aggregates.processes.iter().map(|totals| totals.pid).collect();
+        assert_eq!(pids, vec![20, 10, 11, 30]);
+        assert_eq!(aggregates.processes[1].traffic, TrafficTotals {
+            bytes_uploaded: 200,
+            bytes_downloaded: 20,
+            packets_uploaded: 2,
+            packets_downloaded: 4,
+            connections: 2,
+        });
+
+        assert_eq!(aggregates.executables.len(), 2);
+        assert_eq!(aggregates.executables[0].executable, "/usr/bin/ssh");
+        let curl = &aggregates.executables[1];
+        assert_eq!(curl.pids, vec![10, 11]);
+        assert_eq!((curl.traffic.bytes_uploaded, curl.traffic.connections), (205, 3));
+
+        assert_eq!(aggregates.users.len(), 2);
+        assert_eq!((aggregates.users[0].user.as_deref(), aggregates.users[0].traffic.bytes_uploaded), (Some("alice"), 1000));
+        assert_eq!((aggregates.users[1].uid, aggregates.users[1].pids.clone()), (0, vec![10, 11, 30]));
+    }
+
+    #[test]
+    fn sums_bound_connections_per_container_and_unit() {
+        let container_id = "4f3c2b1a09e8d7c6b5a4f3e2d1c0b9a8f7e6d5c4b3a2918070605040302010ff";
+        let in_cgroup = |pid, inodes, path: &str| ProcessInfo {
+            cgroup: Some(Cgroup::from_path(path)),
+            ..process(pid, "/usr/sbin/nginx", inodes)
+        };
+
+        let mut processes = ProcessInfos::new();
+        processes.insert(10, in_cgroup(10, vec![1], &format!("/system.slice/docker-{}.scope", container_id)));
+        processes.insert(11, in_cgroup(11, vec![2], &format!("/system.slice/docker-{}.scope", container_id)));
+        processes.insert(20, in_cgroup(20, vec![3], "/system.slice/nginx.service"));
+        processes.insert(30, process(30, "/usr/bin/curl", vec![4]));
+
+        let mut connections = vec![
+            connection(1000, 1, 100),
+            connection(1001, 2, 200),
+            connection(1002, 3, 1000),
+            connection(1003, 4, 5),
+        ];
+        let inodes = index_inodes(&processes);
+        for connection in connections.iter_mut() {
+            connection.bind_matching_process(&inodes);
+        }
+
+        let aggregates = aggregate(&connections, |connection| processes.get(&connection.process_id));
+
+        assert_eq!(aggregates.containers.len(), 1);
+        let container = &aggregates.containers[0];
+        assert_eq!((container.runtime, container.id.as_str(), container.pod.as_deref()), (ContainerRuntime::Docker, container_id, None));
+        assert_eq!((container.pids.clone(), container.traffic.bytes_uploaded), (vec![10, 11], 300));
+
+        let units: Vec<(&str, usize)> = aggregates.units.iter()
+            .map(|totals| (totals.unit.as_str(), totals.traffic.connections))
+            .collect();
+        assert_eq!(units, vec![("nginx.service", 1), (format!("docker-{}.scope", container_id).as_str(), 2)]);
+        assert_eq!(aggregates.units[0].slice.as_deref(), Some("system.slice"));
+    }
+}
@@ -0,0 +1,189 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde_derive::Serialize;
+use utoipa::ToSchema;
+
+// Units systemd gives their own cgroup; slices only group them
+const UNIT_SUFFIXES: [&str; 5] = [".service", ".scope", ".socket", ".mount", ".swap"];
+
+// Scope prefixes used when the runtime delegates cgroups to systemd
+const SCOPE_PREFIXES: [(&str, ContainerRuntime); 4] = [
+    ("docker-", ContainerRuntime::Docker),
+    ("libpod-", ContainerRuntime::Podman),
+    ("cri-containerd-", ContainerRuntime::Containerd),
+    ("crio-", ContainerRuntime::Crio),
+];
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ContainerRuntime {
+    Docker,
+    Podman,
+    Containerd,
+    Crio,
+    /// A Kubernetes pod whose cgroup path does not tell the runtime
+    Kubernetes,
+}
+
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, ToSchema)]
+pub struct Container {
+    pub runtime: ContainerRuntime,
+    pub id: String,
+    /// UID of the Kubernetes pod the container belongs to
+    pub pod: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, ToSchema)]
+pub struct Cgroup {
+    pub path: String,
+    pub container: Option<Container>,
+    /// Innermost systemd unit, e.g. `nginx.service` or `docker-<id>.scope`
+    pub unit: Option<String>,
+    /// Innermost systemd slice, e.g. `system.slice`
+    pub slice: Option<String>,
+}
+
+impl Cgroup {
+    // Reads /proc/<pid>/cgroup or a file in the same format
+    pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Cgroup> {
+        Ok(Cgroup::parse(&fs::read_to_string(path)?))
+    }
+
+    // Lines are `hierarchy:controllers:path`. The unified hierarchy (0) wins unless it is the root, as on hybrid
+    // setups, where the named systemd hierarchy has the same layout.
+    pub fn parse(contents: &str) -> Cgroup {
+        let hierarchies: Vec<(&str, &str, &str)> = contents.lines()
+            .filter_map(|line| {
+                let mut fields = line.splitn(3, ':');
+                Some((fields.next()?, fields.next()?, fields.next()?))
+            })
+            .collect();
+
+        let path = hierarchies.iter().find(|(id, _, path)| *id == "0" && *path != "/")
+            .or_else(|| hierarchies.iter().find(|(_, controllers, _)| *controllers == "name=systemd"))
+            .or_else(|| hierarchies.first())
+            .map(|(_, _, path)| *path)
+            .unwrap_or_default();
+
+        Cgroup::from_path(path)
+    }
+
+    pub fn from_path(path: &str) -> Cgroup {
+        let components: Vec<&str> = path.split('/').filter(|component| !component.is_empty()).collect();
+
+        Cgroup {
+            path: path.to_string(),
+            container: container(&components),
+            unit: components.iter().rev()
+                .find(|component| UNIT_SUFFIXES.iter().any(|suffix| component.ends_with(suffix)))
+                .map(|component| component.to_string()),
+            slice: components.iter().rev()
+                .find(|component| component.ends_with(".slice"))
+                .map(|component| component.to_string()),
+        }
+    }
+}
+
+fn is_container_id(value: &str) -> bool {
+    value.len() == 64 && value.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+// `kubepods-burstable-pod<uid>.slice` with the systemd driver, where dashes in the UID become underscores,
+// `pod<uid>` below `kubepods` with the cgroupfs one
+fn pod_uid(components: &[&str]) -> Option<String> {
+    if !components.first().is_some_and(|component| component.starts_with("kubepods")) {
+        return None;
+    }
+
+    components.iter().find_map(|component| {
+        let name = component.strip_suffix(".slice").unwrap_or(component);
+        let uid = name.rsplit_once("-pod").map(|(_, uid)| uid).or_else(|| name.strip_prefix("pod"))?;
+        Some(uid.replace('_', "-"))
+    })
+}
+
+fn container(components: &[&str]) -> Option<Container> {
+    let pod = pod_uid(components);
+
+    for (index, component) in components.iter().enumerate().rev() {
+        if let Some(name) = component.strip_suffix(".scope") {
+            for (prefix, runtime) in SCOPE_PREFIXES.iter() {
+                if let Some(id) = name.strip_prefix(prefix).filter(|id| is_container_id(id)) {
+                    return Some(Container { runtime: *runtime, id: id.to_string(), pod });
+                }
+            }
+        }
+
+        // Runtimes managing cgroups themselves name them after the bare container ID
+        if is_container_id(component) {
+            let runtime = match index.checked_sub(1).map(|parent| components[parent]) {
+                _ if pod.is_some() => ContainerRuntime::Kubernetes,
+                Some("docker") => ContainerRuntime::Docker,
+                Some("libpod_parent") => ContainerRuntime::Podman,
+                _ => ContainerRuntime::Containerd,
+            };
+            return Some(Container { runtime, id: component.to_string(), pod });
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cgroup, Container, ContainerRuntime};
+
+    const DOCKER_ID: &str = "4f3c2b1a09e8d7c6b5a4f3e2d1c0b9a8f7e6d5c4b3a2918070605040302010ff";
+    const PODMAN_ID: &str = "9a8b7c6d5e4f30211203f4e5d6c7b8a9f0e1d2c3b4a5968778695a4b3c2d1e0f";
+    const POD_CONTAINER_ID: &str = "1c2d3e4f5a6b7c8d9e0f1a2b3c4d5e6f7a8b9c0d1e2f3a4b5c6d7e8f9a0b1c2d";
+    const POD_UID: &str = "6f1c2b1e-8a1d-4d7b-9c1e-2f3a4b5c6d7e";
+
+    fn fixture(name: &str) -> Cgroup {
+        Cgroup::read(format!("{}/tests/fixtures/cgroup/{}", env!("CARGO_MANIFEST_DIR"), name)).unwrap()
+    }
+
+    fn container(runtime: ContainerRuntime, id: &str, pod: Option<&str>) -> Option<Container> {
+        Some(Container { runtime, id: id.to_string(), pod: pod.map(str::to_string) })
+    }
+
+    #[test]
+    fn finds_containers() {
+        let docker = fixture("docker");
+        assert_eq!(docker.container, container(ContainerRuntime::Docker, DOCKER_ID, None));
+        assert_eq!(docker.unit, Some(format!("docker-{}.scope", DOCKER_ID)));
+        assert_eq!(docker.slice.as_deref(), Some("system.slice"));
+
+        let docker = fixture("docker-cgroupfs");
+        assert_eq!(docker.path, format!("/docker/{}", DOCKER_ID));
+        assert_eq!(docker.container, container(ContainerRuntime::Docker, DOCKER_ID, None));
+        assert_eq!((docker.unit, docker.slice), (None, None));
+
+        let podman = fixture("podman");
+        assert_eq!(podman.container, container(ContainerRuntime::Podman, PODMAN_ID, None));
+        assert_eq!(podman.slice.as_deref(), Some("user.slice"));
+
+        assert_eq!(fixture("kubepods-containerd").container, container(ContainerRuntime::Containerd, POD_CONTAINER_ID, Some(POD_UID)));
+        assert_eq!(fixture("kubepods-cgroupfs").container, container(ContainerRuntime::Kubernetes, POD_CONTAINER_ID, Some(POD_UID)));
+    }
+
+    #[test]
+    fn finds_systemd_units() {
+        let service = fixture("service");
+        assert_eq!((service.unit.as_deref(), service.slice.as_deref()), (Some("nginx.service"), Some("system.slice")));
+        assert_eq!(service.container, None);
+
+        // The runtime itself is not in a container
+        assert_eq!(fixture("containerd-service").container, None);
+
+        let session = fixture("user-session");
+        assert_eq!(session.unit.as_deref(), Some("vte-spawn-5d2c.scope"));
+        assert_eq!(session.slice.as_deref(), Some("app-org.gnome.Terminal.slice"));
+
+        let init = fixture("init");
+        assert_eq!((init.unit.as_deref(), init.slice), (Some("init.scope"), None));
+
+        assert_eq!(Cgroup::parse(""), Cgroup::default());
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::SystemTime;
+
+use libc::pid_t;
+use serde_derive::Serialize;
+use utoipa::ToSchema;
+
+use crate::structs::connection::{Connection, Connections};
+use crate::structs::rate::Rates;
+
+const REMOVED_LIMIT: usize = 10_000;
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ConnectionChange {
+    Add { id: String, connection: Connection },
+    Update { id: String, connection: Connection },
+    Remove { id: String },
+}
+
+// What clients display of a connection; counters only move together with last_seen
+#[derive(Clone, Debug, PartialEq)]
+struct Revision {
+    last_seen: SystemTime,
+    process_id: pid_t,
+    process_start_time: u64,
+    inode: u64,
+    rates: Rates,
+}
+
+impl From<&Connection> for Revision {
+    fn from(connection: &Connection) -> Self {
+        Revision {
+            last_seen: connection.last_seen,
+            process_id: connection.process_id,
+            process_start_time: connection.process_start_time,
+            inode: connection.inode,
+            rates: connection.rates.clone(),
+        }
+    }
+}
+
+// Remembers what a client was last sent, so only the differences go out next time
+#[derive(Default)]
+pub struct ChangeTracker {
+    known: HashMap<String, Revision>,
+}
+
+impl ChangeTracker {
+    pub fn changes<'a, I: IntoIterator<Item = &'a Connection>>(&mut self, connections: I) -> Vec<ConnectionChange> {
+        let mut changes = Vec::new();
+        let mut current = HashMap::with_capacity(self.known.len());
+
+        for connection in connections {
+            let id = connection.id.clone();
+            let revision = Revision::from(connection);
+
+            match self.known.remove(&id) {
+                None => changes.push(ConnectionChange::Add { id: id.clone(), connection: connection.clone() }),
+                Some(known) if known != revision => changes.push(ConnectionChange::Update { id: id.clone(), connection: connection.clone() }),
+                Some(_) => {}
+            }
+            current.insert(id, revision);
+        }
+
+        changes.extend(self.known.drain().map(|(id, _)| ConnectionChange::Remove { id }));
+        self.known = current;
+
+        changes
+    }
+}
+
+// Numbers every refresh that changed something, so HTTP clients can ask for what changed after the cursor they last saw
+pub struct ChangeLog {
+    cursor: u64,
+    // Removals up to this cursor have been forgotten, so older cursors need a full snapshot
+    horizon: u64,
+    known: HashMap<String, (Revision, u64)>,
+    removed: VecDeque<(String, u64)>,
+}
+
+pub struct ChangesSince {
+    pub changed: HashSet<String>,
+    pub removed: Vec<String>,
+}
+
+impl ChangeLog {
+    // Seeded with the start time so cursors handed out before a restart are recognized as stale
+    pub fn new(seed: u64) -> Self {
+        ChangeLog {
+            cursor: seed,
+            horizon: seed,
+            known: HashMap::new(),
+            removed: VecDeque::new(),
+        }
+    }
+
+    pub fn cursor(&self) -> u64 {
+        self.cursor
+    }
+
+    pub fn record(&mut self, connections: &Connections) {
+        let next = self.cursor + 1;
+        let mut changed = false;
+        let mut seen = HashSet::with_capacity(connections.len());
+
+        for connection in connections {
+            let revision = Revision::from(connection);
+            match self.known.get_mut(&connection.id) {
+                Some((known, _)) if *known == revision => {}
+                Some(entry) => {
+                    *entry = (revision, next);
+                    changed = true;
+                }
+                None => {
+                    self.known.insert(connection.id.clone(), (revision, next));
+                    changed = true;
+                }
+            }
+            seen.insert(connection.id.as_str());
+        }
+
+        let removed: Vec<String> = self.known.keys().filter(|id| !seen.contains(id.as_str())).cloned().collect();
+        for id in removed {
+            self.known.remove(&id);
+            self.removed.push_back((id, next));
+            changed = true;
+        }
+
+        while self.removed.len() > REMOVED_LIMIT {
+            if let Some((_, removed_at)) = self.removed.pop_front() {
+                self.horizon = self.horizon.max(removed_at);
+            }
+        }
+
+        if changed {
+            self.cursor = next;
+        }
+    }
+
+    // None when the cursor is too old or from before a restart
+    pub fn since(&self, cursor: u64) -> Option<ChangesSince> {
+        if cursor < self.horizon || cursor > self.cursor {
+            return None;
+        }
+
+        let changed = self.known.iter()
+            .filter(|(_, (_, changed_at))| *changed_at > cursor)
+            .map(|(id, _)| id.clone())
+            .collect();
+        let removed = self.removed.iter()
+            .filter(|(id, removed_at)| *removed_at > cursor && !self.known.contains_key(id))
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        Some(ChangesSince { changed, removed })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{ChangeLog, ChangeTracker, ConnectionChange};
+    use crate::helpers::testing::connection;
+
+    fn kinds(changes: &[ConnectionChange]) -> Vec<(&'static str, String)> {
+        changes.iter().map(|change| match change {
+            ConnectionChange::Add { id, .. } => ("add", id.clone()),
+            ConnectionChange::Update { id, .. } => ("update", id.clone()),
+            ConnectionChange::Remove { id } => ("remove", id.clone()),
+        }).collect()
+    }
+
+    #[test]
+    fn reports_added_updated_and_removed_connections() {
+        let mut tracker = ChangeTracker::default();
+        let (first, second) = (connection(1000), connection(1001));
+
+        let added = tracker.changes(&vec![first.clone(), second.clone()]);
+        assert_eq!(kinds(&added), vec![("add", first.id.clone()), ("add", second.id.clone())]);
+
+        assert!(tracker.changes(&vec![first.clone(), second.clone()]).is_empty());
+
+        let mut updated = first.clone();
+        updated.bytes_uploaded = 100;
+        updated.last_seen = UNIX_EPOCH + Duration::from_secs(1);
+        let changes = tracker.changes(&vec![updated]);
+        assert_eq!(kinds(&changes), vec![("update", first.id.clone()), ("remove", second.id.clone())]);
+    }
+
+    #[test]
+    fn logs_changes_after_a_cursor() {
+        let mut log = ChangeLog::new(1000);
+        let (first, second) = (connection(1000), connection(1001));
+
+        log.record(&vec![first.clone(), second.clone()]);
+        let after_add = log.cursor();
+        assert_eq!(after_add, 1001);
+        assert_eq!(log.since(1000).unwrap().changed.len(), 2);
+
+        log.record(&vec![first.clone(), second.clone()]);
+        assert_eq!(log.cursor(), after_add);
+
+        let mut updated = first.clone();
+        updated.last_seen = UNIX_EPOCH + Duration::from_secs(1);
+        log.record(&vec![updated]);
+
+        let changes = log.since(after_add).unwrap();
+        assert_eq!(changes.changed.into_iter().collect::<Vec<String>>(), vec![first.id.clone()]);
+        assert_eq!(changes.removed, vec![second.id.clone()]);
+        assert!(log.since(log.cursor()).unwrap().changed.is_empty());
+    }
+
+    #[test]
+    fn rejects_unknown_cursors() {
+        let mut log = ChangeLog::new(1000);
+        log.record(&vec![connection(1000)]);
+
+        assert!(log.since(999).is_none());
+        assert!(log.since(1002).is_none());
+        assert!(log.since(1001).is_some());
+    }
+
+    #[test]
+    fn serializes_events_with_their_type() {
+        let event = serde_json::to_value(ConnectionChange::Remove { id: "00000000000000ff".to_string() }).unwrap();
+
+        assert_eq!(event, serde_json::json!({"type": "remove", "id": "00000000000000ff"}));
+    }
+}
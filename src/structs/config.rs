@@ -0,0 +1,222 @@
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use custom_error::custom_error;
+
+use crate::history::retention::{RetentionPolicy, DAY, HOUR};
+
+const MIN_FLOW_IDLE_TIMEOUT: u64 = 60;
+
+custom_error! {pub ConfigError
+    MissingValue{option: String} = "Missing value for option {option}",
+    InvalidValue{option: String, value: String} = "Invalid value {value:?} for option {option}",
+    UnknownOption{option: String} = "Unknown option {option}",
+    MissingLocalAddresses = "Replaying a capture file requires at least one --local-address",
+    RetentionExceedsRollups{retention_hours: u64, hourly_retention_days: u64} = "--retention-hours {retention_hours} exceeds the {hourly_retention_days} days hourly rollups are kept for",
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReplayMode {
+    Fast,
+    RealTime,
+}
+
+// Which byte count drives `bytes_uploaded`/`bytes_downloaded`, sorting and rates
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ByteAccounting {
+    Wire,
+    Payload,
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub device_name: Option<String>,
+    pub capture_file: Option<PathBuf>,
+    pub replay_mode: ReplayMode,
+    pub local_addresses: Vec<IpAddr>,
+    pub filter: Option<String>,
+    pub rate_windows: Vec<u64>,
+    pub accounting: ByteAccounting,
+    pub snaplen: i32,
+    pub database: Option<PathBuf>,
+    pub history_interval: u64,
+    pub retention_hours: u64,
+    pub hourly_retention_days: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            device_name: None,
+            capture_file: None,
+            replay_mode: ReplayMode::Fast,
+            local_addresses: Vec::new(),
+            filter: None,
+            rate_windows: vec![1, 10, 60],
+            accounting: ByteAccounting::Wire,
+            snaplen: 65535,
+            database: None,
+            history_interval: 60,
+            retention_hours: 48,
+            hourly_retention_days: 30,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<Config, ConfigError> {
+        let mut config = Config::default();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            if !arg.starts_with("--") {
+                config.device_name = Some(arg);
+                continue;
+            }
+
+            let value = args.next().ok_or_else(|| ConfigError::MissingValue { option: arg.clone() })?;
+            match arg.as_str() {
+                "--file" => config.capture_file = Some(PathBuf::from(value)),
+                "--replay" => config.replay_mode = match value.as_str() {
+                    "fast" => ReplayMode::Fast,
+                    "realtime" => ReplayMode::RealTime,
+                    _ => return Err(ConfigError::InvalidValue { option: arg, value }),
+                },
+                "--local-address" => match value.parse() {
+                    Ok(address) => config.local_addresses.push(address),
+                    Err(_) => return Err(ConfigError::InvalidValue { option: arg, value }),
+                },
+                "--filter" => config.filter = Some(value),
+                "--accounting" => config.accounting = match value.as_str() {
+                    "wire" => ByteAccounting::Wire,
+                    "payload" => ByteAccounting::Payload,
+                    _ => return Err(ConfigError::InvalidValue { option: arg, value }),
+                },
+                "--snaplen" => config.snaplen = match value.parse() {
+                    Ok(snaplen) if snaplen > 0 => snaplen,
+                    _ => return Err(ConfigError::InvalidValue { option: arg, value }),
+                },
+                "--database" => config.database = Some(PathBuf::from(value)),
+                "--history-interval" => config.history_interval = match value.parse() {
+                    Ok(history_interval) if history_interval > 0 => history_interval,
+                    _ => return Err(ConfigError::InvalidValue { option: arg, value }),
+                },
+                "--retention-hours" => config.retention_hours = match value.parse() {
+                    Ok(retention_hours) if retention_hours > 0 => retention_hours,
+                    _ => return Err(ConfigError::InvalidValue { option: arg, value }),
+                },
+                "--hourly-retention-days" => config.hourly_retention_days = match value.parse() {
+                    Ok(hourly_retention_days) if hourly_retention_days > 0 => hourly_retention_days,
+                    _ => return Err(ConfigError::InvalidValue { option: arg, value }),
+                },
+                "--rate-windows" => config.rate_windows = match parse_rate_windows(&value) {
+                    Some(rate_windows) => rate_windows,
+                    None => return Err(ConfigError::InvalidValue { option: arg, value }),
+                },
+                _ => return Err(ConfigError::UnknownOption { option: arg }),
+            }
+        }
+
+        if config.capture_file.is_some() && config.local_addresses.is_empty() {
+            return Err(ConfigError::MissingLocalAddresses);
+        }
+
+        // Hourly rollups must cover every hour raw samples do, or history queries read those hours from both
+        if config.retention_hours > config.hourly_retention_days * 24 {
+            return Err(ConfigError::RetentionExceedsRollups {
+                retention_hours: config.retention_hours,
+                hourly_retention_days: config.hourly_retention_days,
+            });
+        }
+
+        Ok(config)
+    }
+
+    // Samples older than the largest rate window are no longer needed
+    pub fn rate_horizon(&self) -> u64 {
+        self.rate_windows.last().copied().unwrap_or(0)
+    }
+
+    // Idle flows are kept for at least two history intervals, so that a sample records their last packets before they
+    // are evicted
+    pub fn flow_idle_timeout(&self) -> Duration {
+        Duration::from_secs(MIN_FLOW_IDLE_TIMEOUT.max(2 * self.history_interval))
+    }
+
+    pub fn retention_policy(&self) -> RetentionPolicy {
+        RetentionPolicy {
+            raw: self.retention_hours * HOUR,
+            hourly: self.hourly_retention_days * DAY,
+            write_delay: self.history_interval,
+        }
+    }
+}
+
+fn parse_rate_windows(value: &str) -> Option<Vec<u64>> {
+    let mut rate_windows = value.split(',')
+        .map(|window| window.trim().trim_end_matches('s').parse().ok().filter(|&window| window > 0))
+        .collect::<Option<Vec<u64>>>()?;
+    rate_windows.sort_unstable();
+    rate_windows.dedup();
+
+    Some(rate_windows)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use super::{ByteAccounting, Config, ConfigError, ReplayMode};
+
+    fn parse(args: &[&str]) -> Result<Config, ConfigError> {
+        Config::from_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn keeps_idle_flows_for_two_history_intervals() {
+        assert_eq!(parse(&["--history-interval", "10"]).unwrap().flow_idle_timeout(), Duration::from_secs(60));
+        assert_eq!(parse(&["--history-interval", "300"]).unwrap().flow_idle_timeout(), Duration::from_secs(600));
+    }
+
+    #[test]
+    fn parses_replay_options() {
+        let config = parse(&[
+            "--file", "capture.pcap",
+            "--replay", "realtime",
+            "--local-address", "192.168.1.10",
+            "--local-address", "2001:db8::2",
+            "--filter", "tcp port 443",
+            "--accounting", "payload",
+            "--rate-windows", "60s,1,10,1",
+        ]).unwrap();
+
+        assert_eq!(config.capture_file, Some(PathBuf::from("capture.pcap")));
+        assert_eq!(config.replay_mode, ReplayMode::RealTime);
+        assert_eq!(config.local_addresses, vec!["192.168.1.10".parse::<IpAddr>().unwrap(), "2001:db8::2".parse().unwrap()]);
+        assert_eq!(config.filter.as_deref(), Some("tcp port 443"));
+        assert_eq!(config.accounting, ByteAccounting::Payload);
+        assert_eq!(config.rate_windows, vec![1, 10, 60]);
+        assert_eq!(config.device_name, None);
+
+        assert_eq!(parse(&["eth0"]).unwrap().device_name.as_deref(), Some("eth0"));
+    }
+
+    #[test]
+    fn rejects_invalid_arguments() {
+        assert!(matches!(parse(&["--file", "capture.pcap"]), Err(ConfigError::MissingLocalAddresses)));
+        assert!(matches!(parse(&["--replay"]), Err(ConfigError::MissingValue { .. })));
+        assert!(matches!(parse(&["--replay", "slow"]), Err(ConfigError::InvalidValue { .. })));
+        assert!(matches!(parse(&["--local-address", "localhost"]), Err(ConfigError::InvalidValue { .. })));
+        assert!(matches!(parse(&["--snaplen", "0"]), Err(ConfigError::InvalidValue { .. })));
+        assert!(matches!(parse(&["--rate-windows", "1,0"]), Err(ConfigError::InvalidValue { .. })));
+        assert!(matches!(parse(&["--verbose", "1"]), Err(ConfigError::UnknownOption { .. })));
+        assert!(matches!(
+            parse(&["--retention-hours", "100", "--hourly-retention-days", "1"]),
+            Err(ConfigError::RetentionExceedsRollups { retention_hours: 100, hourly_retention_days: 1 })
+        ));
+        assert!(parse(&["--retention-hours", "24", "--hourly-retention-days", "1"]).is_ok());
+    }
+}
@@ -1,64 +1,120 @@
 use std::cmp::Ordering;
+use std::collections::HashSet;
 use std::hash::{Hash, Hasher};
 use std::net::SocketAddr;
-use std::time::SystemTime;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use libc::pid_t;
 use procfs::net::{TcpNetEntry, UdpNetEntry};
-use serde_derive::Serialize;
+use serde_derive::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
 
-use crate::structs::process::ProcessInfos;
+use crate::structs::process::{InodeIndex, ProcessKey};
+use crate::structs::rate::{Rate, RateMeter, Rates};
 
-#[derive(Hash, Clone, Debug, Eq, PartialEq, Serialize)]
+#[derive(Hash, Clone, Debug, Eq, PartialEq, Deserialize, Serialize, ToSchema)]
 pub enum TransportType {
+    #[serde(alias = "tcp")]
     Tcp,
+    #[serde(alias = "udp")]
     Udp,
 }
 
-#[derive(Clone, Debug, Serialize, Eq)]
+// The local endpoint is always the source
+#[derive(Clone, Debug, Serialize, ToSchema)]
 pub struct Connection {
+    pub id: String,
+    #[schema(value_type = String, example = "192.168.1.10:40000")]
     pub source: SocketAddr,
+    #[schema(value_type = String, example = "93.184.216.34:443")]
     pub destination: SocketAddr,
     pub inode: u64,
+    #[schema(value_type = i32)]
     pub process_id: pid_t,
+    /// Tells a process apart from an earlier one with the same pid
+    pub process_start_time: u64,
     pub transport_type: TransportType,
     pub bytes_uploaded: usize,
     pub bytes_downloaded: usize,
+    pub packets_uploaded: usize,
+    pub packets_downloaded: usize,
+    pub wire_bytes_uploaded: usize,
+    pub wire_bytes_downloaded: usize,
+    pub payload_bytes_uploaded: usize,
+    pub payload_bytes_downloaded: usize,
+    #[schema(value_type = Timestamp)]
     pub first_seen: SystemTime,
+    #[schema(value_type = Timestamp)]
     pub last_seen: SystemTime,
+    #[schema(value_type = Vec<Rate>)]
+    pub rates: Rates,
+    #[serde(skip)]
+    pub rate_meter: RateMeter,
+}
+
+// How serde writes a SystemTime, only used to describe it in the OpenAPI document
+#[allow(dead_code)]
+#[derive(ToSchema)]
+pub struct Timestamp {
+    pub secs_since_epoch: u64,
+    pub nanos_since_epoch: u32,
 }
 
 pub type Connections = Vec<Connection>;
+pub type FlowTable = HashSet<Connection>;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SortKey {
+    Total,
+    Upload,
+    Download,
+    Rate,
+    LastSeen,
+    FirstSeen,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, ToSchema)]
+pub enum SortOrder {
+    #[serde(rename = "asc")]
+    Ascending,
+    #[serde(rename = "desc")]
+    Descending,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, IntoParams)]
+#[serde(default)]
+#[into_params(parameter_in = Query)]
+pub struct ConnectionSort {
+    pub sort: SortKey,
+    pub order: SortOrder,
+}
+
+impl Default for ConnectionSort {
+    fn default() -> Self {
+        ConnectionSort {
+            sort: SortKey::Total,
+            order: SortOrder::Descending,
+        }
+    }
+}
 
 impl From<TcpNetEntry> for Connection {
     fn from(entry: TcpNetEntry) -> Self {
-        Connection {
-            source: entry.local_address,
-            destination: entry.remote_address,
-            inode: entry.inode,
-            process_id: 0,
-            transport_type: TransportType::Tcp,
-            bytes_uploaded: 0,
-            bytes_downloaded: 0,
-            first_seen: SystemTime::now(),
-            last_seen: SystemTime::now(),
-        }
+        let mut connection = Connection::new(entry.local_address, entry.remote_address, TransportType::Tcp, SystemTime::now());
+        connection.inode = entry.inode;
+        connection
     }
 }
 
 impl From<UdpNetEntry> for Connection {
     fn from(entry: UdpNetEntry) -> Self {
-        Connection {
-            source: entry.local_address,
-            destination: entry.remote_address,
-            inode: entry.inode,
-            process_id: 0,
-            transport_type: TransportType::Udp,
-            bytes_uploaded: 0,
-            bytes_downloaded: 0,
-            first_seen: SystemTime::now(),
-            last_seen: SystemTime::now(),
-        }
+        let mut connection = Connection::new(entry.local_address, entry.remote_address, TransportType::Udp, SystemTime::now());
+        connection.inode = entry.inode;
+        connection
     }
 }
 
@@ -70,6 +126,8 @@ impl PartialEq for Connection {
     }
 }
 
+impl Eq for Connection {}
+
 impl Hash for Connection {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self.transport_type.hash(state);
@@ -85,25 +143,109 @@ impl Hash for Connection {
 
 impl Ord for Connection {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.partial_cmp(other).unwrap()
+        self.compare_by(other, SortKey::Total)
     }
 }
 
 impl PartialOrd for Connection {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some((self.bytes_uploaded + self.bytes_uploaded).cmp(&(other.bytes_uploaded + other.bytes_downloaded)))
+        Some(self.cmp(other))
     }
 }
 
 impl Connection {
-    pub fn bind_matching_process (&mut self, processes: &ProcessInfos) {
-        for (process_id, process_info) in processes {
-            if process_info.inodes.contains(&self.inode) {
-                self.process_id = *process_id;
-                break;
+    pub fn new(source: SocketAddr, destination: SocketAddr, transport_type: TransportType, seen_at: SystemTime) -> Self {
+        let mut connection = Connection::unidentified(source, destination, transport_type, seen_at);
+        connection.identify();
+        connection
+    }
+
+    // Without an id, which is only worth computing once a packet starts a new flow
+    pub fn unidentified(source: SocketAddr, destination: SocketAddr, transport_type: TransportType, seen_at: SystemTime) -> Self {
+        Connection {
+            id: String::new(),
+            source,
+            destination,
+            inode: 0,
+            process_id: 0,
+            process_start_time: 0,
+            transport_type,
+            bytes_uploaded: 0,
+            bytes_downloaded: 0,
+            packets_uploaded: 0,
+            packets_downloaded: 0,
+            wire_bytes_uploaded: 0,
+            wire_bytes_downloaded: 0,
+            payload_bytes_uploaded: 0,
+            payload_bytes_downloaded: 0,
+            first_seen: seen_at,
+            last_seen: seen_at,
+            rates: Rates::new(),
+            rate_meter: RateMeter::default(),
+        }
+    }
+
+    pub fn identify(&mut self) {
+        self.id = connection_id(self.source, self.destination, &self.transport_type, self.first_seen);
+    }
+
+    pub fn bytes_total(&self) -> usize {
+        self.bytes_uploaded + self.bytes_downloaded
+    }
+
+    // Combined upload and download rate over the shortest rate window
+    pub fn rate(&self) -> f64 {
+        self.rates.first().map(|rate| rate.upload + rate.download).unwrap_or(0.0)
+    }
+
+    pub fn compare_by(&self, other: &Self, key: SortKey) -> Ordering {
+        match key {
+            SortKey::Total => self.bytes_total().cmp(&other.bytes_total()),
+            SortKey::Upload => self.bytes_uploaded.cmp(&other.bytes_uploaded),
+            SortKey::Download => self.bytes_downloaded.cmp(&other.bytes_downloaded),
+            SortKey::Rate => self.rate().total_cmp(&other.rate()),
+            SortKey::LastSeen => self.last_seen.cmp(&other.last_seen),
+            SortKey::FirstSeen => self.first_seen.cmp(&other.first_seen),
+        }
+    }
+
+    // Returns whether a running process holds the socket
+    pub fn bind_matching_process(&mut self, inodes: &InodeIndex) -> bool {
+        match inodes.get(&self.inode) {
+            Some(process) => {
+                self.bind(*process);
+                true
             }
+            None => false,
         }
     }
+
+    pub fn bind(&mut self, process: ProcessKey) {
+        self.process_id = process.pid;
+        self.process_start_time = process.start_time;
+    }
+
+    pub fn process_key(&self) -> ProcessKey {
+        ProcessKey { pid: self.process_id, start_time: self.process_start_time }
+    }
+}
+
+// Same for both directions of a flow and across restarts, unlike the std hasher; first_seen tells reused tuples apart
+fn connection_id(source: SocketAddr, destination: SocketAddr, transport_type: &TransportType, first_seen: SystemTime) -> String {
+    let (lower, upper) = if source < destination { (source, destination) } else { (destination, source) };
+    let first_seen = first_seen.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+    let key = format!("{:?} {} {} {}", transport_type, lower, upper, first_seen);
+
+    let hash = key.bytes().fold(FNV_OFFSET_BASIS, |hash, byte| (hash ^ byte as u64).wrapping_mul(FNV_PRIME));
+
+    format!("{:016x}", hash)
+}
+
+pub fn sort_connections(connections: &mut Connections, sort: ConnectionSort) {
+    match sort.order {
+        SortOrder::Ascending => connections.sort_unstable_by(|a, b| a.compare_by(b, sort.sort)),
+        SortOrder::Descending => connections.sort_unstable_by(|a, b| b.compare_by(a, sort.sort)),
+    }
 }
 
 //fn ip_to_string(ip: &Vec<u8>) -> String {
@@ -134,3 +276,79 @@ impl Connection {
 //        connection.end()
 //    }
 //}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{sort_connections, Connection, ConnectionSort, Connections, SortKey, SortOrder, TransportType};
+    use crate::helpers::testing;
+    use crate::structs::rate::Rate;
+
+    fn connection(port: u16, bytes_uploaded: usize, bytes_downloaded: usize, first_seen: u64, last_seen: u64) -> Connection {
+        Connection {
+            bytes_uploaded,
+            bytes_downloaded,
+            first_seen: UNIX_EPOCH + Duration::from_secs(first_seen),
+            last_seen: UNIX_EPOCH + Duration::from_secs(last_seen),
+            rates: vec![Rate {
+                window: 1,
+                upload: (bytes_uploaded / 10) as f64,
+                download: (bytes_downloaded / 100) as f64,
+            }],
+            ..testing::connection(port)
+        }
+    }
+
+    fn sorted_ports(sort: SortKey, order: SortOrder) -> Vec<u16> {
+        let mut connections: Connections = vec![
+            connection(1, 100, 0, 10, 20),
+            connection(2, 10, 500, 0, 100),
+            connection(3, 300, 50, 50, 51),
+        ];
+        sort_connections(&mut connections, ConnectionSort { sort, order });
+
+        connections.iter().map(|connection| connection.source.port()).collect()
+    }
+
+    #[test]
+    fn identifies_both_directions_alike() {
+        let outgoing = testing::connection(1000);
+        let incoming = Connection::new(outgoing.destination, outgoing.source, TransportType::Tcp, UNIX_EPOCH);
+        let reused = Connection::new(outgoing.source, outgoing.destination, TransportType::Tcp, UNIX_EPOCH + Duration::from_secs(1));
+
+        assert_eq!(outgoing.id, incoming.id);
+        assert_eq!(outgoing.id.len(), 16);
+        assert_ne!(outgoing.id, testing::connection(1001).id);
+        assert_ne!(outgoing.id, reused.id);
+    }
+
+    #[test]
+    fn orders_by_uploaded_plus_downloaded() {
+        assert!(connection(1, 10, 500, 0, 0) > connection(2, 300, 0, 0, 0));
+    }
+
+    #[test]
+    fn sorts_by_each_key() {
+        assert_eq!(sorted_ports(SortKey::Total, SortOrder::Descending), vec![2, 3, 1]);
+        assert_eq!(sorted_ports(SortKey::Upload, SortOrder::Descending), vec![3, 1, 2]);
+        assert_eq!(sorted_ports(SortKey::Download, SortOrder::Descending), vec![2, 3, 1]);
+        assert_eq!(sorted_ports(SortKey::Rate, SortOrder::Descending), vec![3, 1, 2]);
+        assert_eq!(sorted_ports(SortKey::LastSeen, SortOrder::Descending), vec![2, 3, 1]);
+        assert_eq!(sorted_ports(SortKey::FirstSeen, SortOrder::Descending), vec![3, 1, 2]);
+    }
+
+    #[test]
+    fn sorts_ascending() {
+        assert_eq!(sorted_ports(SortKey::Total, SortOrder::Ascending), vec![1, 3, 2]);
+        assert_eq!(sorted_ports(SortKey::FirstSeen, SortOrder::Ascending), vec![2, 1, 3]);
+    }
+
+    #[test]
+    fn defaults_to_total_descending() {
+        let sort = ConnectionSort::default();
+
+        assert_eq!(sort.sort, SortKey::Total);
+        assert_eq!(sort.order, SortOrder::Descending);
+    }
+}
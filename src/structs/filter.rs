@@ -0,0 +1,169 @@
+use std::convert::TryFrom;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use libc::pid_t;
+use serde_derive::Deserialize;
+use utoipa::IntoParams;
+
+use crate::structs::connection::{Connection, TransportType};
+
+// An address with an optional prefix length, e.g. `10.0.0.0/8` or `2001:db8::1`
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(try_from = "String")]
+pub struct AddressRange {
+    pub network: IpAddr,
+    pub prefix: u8,
+}
+
+impl AddressRange {
+    pub fn contains(&self, address: IpAddr) -> bool {
+        match (self.network, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(address) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for AddressRange {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid address or CIDR range {:?}", value);
+        let (network, prefix) = match value.split_once('/') {
+            Some((network, prefix)) => (network, Some(prefix)),
+            None => (value, None),
+        };
+
+        let network: IpAddr = network.parse().map_err(|_| invalid())?;
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().ok().filter(|&prefix| prefix <= max_prefix).ok_or_else(invalid)?,
+            None => max_prefix,
+        };
+
+        Ok(AddressRange { network, prefix })
+    }
+}
+
+impl TryFrom<String> for AddressRange {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+// Every field that is set has to match; `port` and `address` match either end of the connection.
+// Also read from query strings, hence the shorter aliases.
+#[derive(Clone, Debug, Default, Deserialize, IntoParams, PartialEq)]
+#[serde(default)]
+#[into_params(parameter_in = Query)]
+pub struct ConnectionFilter {
+    /// Also accepted as `pid`
+    #[serde(alias = "pid")]
+    #[param(value_type = Option<i32>)]
+    pub process_id: Option<pid_t>,
+    /// Also accepted as `protocol`, in lower case too
+    #[serde(alias = "protocol")]
+    pub transport_type: Option<TransportType>,
+    pub destination_port: Option<u16>,
+    pub port: Option<u16>,
+    /// An address or CIDR range, e.g. `10.0.0.0/8`
+    #[param(value_type = Option<String>)]
+    pub address: Option<AddressRange>,
+    pub min_bytes: Option<usize>,
+}
+
+impl ConnectionFilter {
+    pub fn matches(&self, connection: &Connection) -> bool {
+        self.process_id.is_none_or(|process_id| connection.process_id == process_id)
+            && self.transport_type.as_ref().is_none_or(|transport_type| &connection.transport_type == transport_type)
+            && self.destination_port.is_none_or(|port| connection.destination.port() == port)
+            && self.port.is_none_or(|port| connection.source.port() == port || connection.destination.port() == port)
+            && self.address.is_none_or(|range| range.contains(connection.source.ip()) || range.contains(connection.destination.ip()))
+            && self.min_bytes.is_none_or(|min_bytes| connection.bytes_total() >= min_bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::web::Query;
+
+    use super::{AddressRange, ConnectionFilter};
+    use crate::helpers::testing::connection_to;
+    use crate::structs::connection::{Connection, TransportType};
+
+    fn connection(transport_type: TransportType, destination_port: u16, process_id: i32) -> Connection {
+        Connection { process_id, ..connection_to(transport_type, 40000, ([10, 0, 0, 2], destination_port)) }
+    }
+
+    #[test]
+    fn empty_filter_matches_everything() {
+        assert!(ConnectionFilter::default().matches(&connection(TransportType::Tcp, 443, 1)));
+    }
+
+    #[test]
+    fn requires_every_field_to_match() {
+        let filter = ConnectionFilter {
+            process_id: Some(1234),
+            transport_type: Some(TransportType::Udp),
+            destination_port: Some(443),
+            ..ConnectionFilter::default()
+        };
+
+        assert!(filter.matches(&connection(TransportType::Udp, 443, 1234)));
+        assert!(!filter.matches(&connection(TransportType::Tcp, 443, 1234)));
+        assert!(!filter.matches(&connection(TransportType::Udp, 80, 1234)));
+        assert!(!filter.matches(&connection(TransportType::Udp, 443, 1)));
+    }
+
+    #[test]
+    fn matches_ports_addresses_and_volume_on_either_end() {
+        let mut connection = connection(TransportType::Tcp, 443, 1);
+        connection.bytes_uploaded = 100;
+
+        let matching = |filter: ConnectionFilter| filter.matches(&connection);
+        assert!(matching(ConnectionFilter { port: Some(40000), ..ConnectionFilter::default() }));
+        assert!(matching(ConnectionFilter { port: Some(443), ..ConnectionFilter::default() }));
+        assert!(!matching(ConnectionFilter { port: Some(80), ..ConnectionFilter::default() }));
+        assert!(matching(ConnectionFilter { address: Some("10.0.0.0/24".parse().unwrap()), ..ConnectionFilter::default() }));
+        assert!(!matching(ConnectionFilter { address: Some("192.168.0.0/16".parse().unwrap()), ..ConnectionFilter::default() }));
+        assert!(matching(ConnectionFilter { min_bytes: Some(100), ..ConnectionFilter::default() }));
+        assert!(!matching(ConnectionFilter { min_bytes: Some(101), ..ConnectionFilter::default() }));
+    }
+
+    #[test]
+    fn parses_address_ranges() {
+        let range: AddressRange = "10.1.0.0/16".parse().unwrap();
+        assert!(range.contains("10.1.255.1".parse().unwrap()));
+        assert!(!range.contains("10.2.0.1".parse().unwrap()));
+        assert!(!range.contains("::1".parse().unwrap()));
+
+        let single: AddressRange = "2001:db8::1".parse().unwrap();
+        assert_eq!(single.prefix, 128);
+        assert!(single.contains("2001:db8::1".parse().unwrap()));
+        assert!(!single.contains("2001:db8::2".parse().unwrap()));
+
+        assert!("0.0.0.0/0".parse::<AddressRange>().unwrap().contains("8.8.8.8".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<AddressRange>().is_err());
+        assert!("example.com".parse::<AddressRange>().is_err());
+    }
+
+    #[test]
+    fn reads_query_string_aliases() {
+        let filter = Query::<ConnectionFilter>::from_query("pid=42&protocol=udp&address=10.0.0.0/8&min_bytes=5").unwrap().into_inner();
+
+        assert_eq!(filter.process_id, Some(42));
+        assert_eq!(filter.transport_type, Some(TransportType::Udp));
+        assert_eq!(filter.address, Some("10.0.0.0/8".parse().unwrap()));
+        assert_eq!(filter.min_bytes, Some(5));
+    }
+}
@@ -0,0 +1,90 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use crate::structs::aggregate::TrafficTotals;
+
+// Counters the capture threads update as they go, read by the metrics endpoint
+#[derive(Debug, Default)]
+pub struct CaptureHealth {
+    pub threads_alive: AtomicUsize,
+    pub packets_parsed: AtomicU64,
+    pub packets_ignored: AtomicU64,
+    pub parse_errors: AtomicU64,
+    // By remote address rather than flow, as flows are evicted once idle
+    pub remotes: Mutex<BTreeMap<IpAddr, TrafficTotals>>,
+}
+
+pub type SharedHealth = Arc<CaptureHealth>;
+
+// Counts a capture thread as alive for as long as it is held, including when the thread panics
+pub struct AliveGuard<'a> {
+    health: &'a CaptureHealth,
+}
+
+impl CaptureHealth {
+    pub fn alive(&self) -> AliveGuard<'_> {
+        self.threads_alive.fetch_add(1, Ordering::Relaxed);
+        AliveGuard { health: self }
+    }
+
+    pub fn record_packet(&self, parsed: bool) {
+        if parsed {
+            self.packets_parsed.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.parse_errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Packets without TCP or UDP traffic from or to a local address, such as ARP or ICMP
+    pub fn record_ignored(&self) {
+        self.packets_ignored.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_remote(&self, remote: IpAddr, bytes: usize, uploaded: bool) {
+        let mut remotes = self.remotes.lock().unwrap();
+        let traffic = remotes.entry(remote).or_default();
+        if uploaded {
+            traffic.bytes_uploaded += bytes;
+            traffic.packets_uploaded += 1;
+        } else {
+            traffic.bytes_downloaded += bytes;
+            traffic.packets_downloaded += 1;
+        }
+    }
+}
+
+impl Drop for AliveGuard<'_> {
+    fn drop(&mut self) {
+        self.health.threads_alive.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Cost of the /proc scans, updated by the processes thread
+#[derive(Debug, Default)]
+pub struct ScanHealth {
+    pub scans: AtomicU64,
+    pub scan_micros: AtomicU64,
+    pub last_scan_micros: AtomicU64,
+    pub interval_millis: AtomicU64,
+    pub processes: AtomicUsize,
+    pub fd_tables_read: AtomicU64,
+}
+
+pub type SharedScanHealth = Arc<ScanHealth>;
+
+impl ScanHealth {
+    pub fn record_processes(&self, processes: usize, fd_tables_read: usize) {
+        self.processes.store(processes, Ordering::Relaxed);
+        self.fd_tables_read.fetch_add(fd_tables_read as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_scan(&self, duration: Duration, next_interval: Duration) {
+        self.scans.fetch_add(1, Ordering::Relaxed);
+        self.scan_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.last_scan_micros.store(duration.as_micros() as u64, Ordering::Relaxed);
+        self.interval_millis.store(next_interval.as_millis() as u64, Ordering::Relaxed);
+    }
+}
@@ -1,3 +1,12 @@
+pub mod aggregate;
+pub mod cgroup;
+pub mod changes;
+pub mod config;
 pub mod connection;
+pub mod filter;
+pub mod health;
 pub mod process;
+pub mod rate;
 pub mod receivers;
+pub mod state;
+pub mod tree;
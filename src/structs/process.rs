@@ -2,26 +2,82 @@ use libc::pid_t;
 use std::collections::HashMap;
 use serde::{Serialize, Serializer};
 use serde::ser::SerializeStruct;
+use utoipa::ToSchema;
 
-#[derive(Clone, Debug)]
+use crate::structs::cgroup::Cgroup;
+use crate::structs::rate::{Rate, Rates};
+
+#[derive(Clone, Debug, Default, ToSchema)]
 pub struct ProcessInfo {
+    #[schema(value_type = i32)]
     pub pid: pid_t,
+    /// Clock ticks after boot, as in /proc/<pid>/stat
+    pub start_time: u64,
+    /// Kept while connections are still attributed to the process
+    pub exited: bool,
+    #[schema(value_type = i32)]
+    pub parent_pid: pid_t,
+    /// Process name from /proc/<pid>/comm, at most 15 characters
+    pub name: String,
     pub command: String,
     pub executable: String,
+    pub uid: u32,
+    pub effective_uid: u32,
+    /// Name of the real user, unless the uid has no entry
+    pub user: Option<String>,
+    /// Unknown when /proc/<pid>/cgroup could not be read
+    pub cgroup: Option<Cgroup>,
+    #[schema(ignore)]
     pub inodes: Vec<u64>,
+    #[schema(value_type = Vec<Rate>)]
+    pub rates: Rates,
 }
 
 pub type ProcessInfos = HashMap<pid_t, ProcessInfo>;
 
+// Pids get reused, the pid together with the start time does not
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct ProcessKey {
+    pub pid: pid_t,
+    pub start_time: u64,
+}
+
+pub type ExitedProcesses = HashMap<ProcessKey, ProcessInfo>;
+
+// Socket inode to the process holding it
+pub type InodeIndex = HashMap<u64, ProcessKey>;
+
+// One complete scan of /proc, sent by the processes thread
+#[derive(Clone, Debug, Default)]
+pub struct ProcessTable {
+    pub processes: ProcessInfos,
+    pub inodes: InodeIndex,
+}
+
+impl ProcessInfo {
+    pub fn key(&self) -> ProcessKey {
+        ProcessKey { pid: self.pid, start_time: self.start_time }
+    }
+}
+
 impl Serialize for ProcessInfo {
     fn serialize<S>(&self, serializer: S) -> Result<<S as Serializer>::Ok, <S as Serializer>::Error> where
         S: Serializer {
-        let mut process_info = serializer.serialize_struct("ProcessInfo", 4)?;
+        let mut process_info = serializer.serialize_struct("ProcessInfo", 13)?;
 
         process_info.serialize_field("pid", &self.pid)?;
+        process_info.serialize_field("start_time", &self.start_time)?;
+        process_info.serialize_field("exited", &self.exited)?;
+        process_info.serialize_field("parent_pid", &self.parent_pid)?;
+        process_info.serialize_field("name", &self.name)?;
         process_info.serialize_field("command", &self.command)?;
         process_info.serialize_field("executable", &self.executable)?;
+        process_info.serialize_field("uid", &self.uid)?;
+        process_info.serialize_field("effective_uid", &self.effective_uid)?;
+        process_info.serialize_field("user", &self.user)?;
+        process_info.serialize_field("cgroup", &self.cgroup)?;
         process_info.skip_field("inodes")?;
+        process_info.serialize_field("rates", &self.rates)?;
 
         process_info.end()
     }
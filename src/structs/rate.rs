@@ -0,0 +1,116 @@
+use std::collections::VecDeque;
+
+use serde_derive::Serialize;
+use utoipa::ToSchema;
+
+#[derive(Clone, Copy, Debug)]
+struct RateSample {
+    second: u64,
+    uploaded: usize,
+    downloaded: usize,
+}
+
+// Bytes transferred per second, kept only as far back as the largest rate window
+#[derive(Clone, Debug, Default)]
+pub struct RateMeter {
+    samples: VecDeque<RateSample>,
+}
+
+// Average bytes per second over the last `window` seconds
+#[derive(Clone, Debug, PartialEq, Serialize, ToSchema)]
+pub struct Rate {
+    pub window: u64,
+    pub upload: f64,
+    pub download: f64,
+}
+
+pub type Rates = Vec<Rate>;
+
+impl RateMeter {
+    pub fn record(&mut self, second: u64, uploaded: usize, downloaded: usize, horizon: u64) {
+        match self.samples.back_mut() {
+            Some(sample) if sample.second == second => {
+                sample.uploaded += uploaded;
+                sample.downloaded += downloaded;
+            }
+            _ => self.samples.push_back(RateSample { second, uploaded, downloaded }),
+        }
+
+        while let Some(sample) = self.samples.front() {
+            if sample.second + horizon >= second {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+
+    // Rates only cover complete seconds, so the second `now` is still in is left out
+    pub fn rates(&self, now: u64, windows: &[u64]) -> Rates {
+        windows.iter().map(|&window| {
+            let (uploaded, downloaded) = self.samples.iter()
+                .filter(|sample| sample.second < now && sample.second + window >= now)
+                .fold((0, 0), |(uploaded, downloaded), sample| {
+                    (uploaded + sample.uploaded, downloaded + sample.downloaded)
+                });
+
+            Rate {
+                window,
+                upload: uploaded as f64 / window as f64,
+                download: downloaded as f64 / window as f64,
+            }
+        }).collect()
+    }
+}
+
+pub fn add_rates(total: &mut Rates, rates: &[Rate]) {
+    for rate in rates {
+        match total.iter_mut().find(|current| current.window == rate.window) {
+            Some(current) => {
+                current.upload += rate.upload;
+                current.download += rate.download;
+            }
+            None => total.push(rate.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{add_rates, Rate, RateMeter};
+
+    #[test]
+    fn averages_complete_seconds_per_window() {
+        let mut meter = RateMeter::default();
+        meter.record(95, 1000, 0, 60);
+        meter.record(99, 100, 50, 60);
+        meter.record(99, 100, 50, 60);
+        meter.record(100, 5000, 5000, 60);
+
+        let rates = meter.rates(100, &[1, 10]);
+
+        assert_eq!(rates[0], Rate { window: 1, upload: 200.0, download: 100.0 });
+        assert_eq!(rates[1], Rate { window: 10, upload: 120.0, download: 10.0 });
+    }
+
+    #[test]
+    fn forgets_samples_beyond_horizon() {
+        let mut meter = RateMeter::default();
+        meter.record(10, 1000, 1000, 60);
+        meter.record(100, 10, 10, 60);
+
+        assert_eq!(meter.samples.len(), 1);
+        assert_eq!(meter.rates(101, &[60])[0].upload, 10.0 / 60.0);
+    }
+
+    #[test]
+    fn sums_rates_by_window() {
+        let mut total = vec![];
+        add_rates(&mut total, &[Rate { window: 1, upload: 1.0, download: 2.0 }]);
+        add_rates(&mut total, &[Rate { window: 1, upload: 3.0, download: 4.0 }, Rate { window: 10, upload: 1.0, download: 1.0 }]);
+
+        assert_eq!(total, vec![
+            Rate { window: 1, upload: 4.0, download: 6.0 },
+            Rate { window: 10, upload: 1.0, download: 1.0 },
+        ]);
+    }
+}
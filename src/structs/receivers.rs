@@ -1,6 +1,6 @@
 use crate::structs::connection::Connections;
-use crate::structs::process::ProcessInfos;
+use crate::structs::process::ProcessTable;
 
 pub type ConnectionsReceiver = single_value_channel::Receiver<Option<Connections>>;
-pub type ProcessesReceiver = single_value_channel::Receiver<Option<ProcessInfos>>;
+pub type ProcessesReceiver = single_value_channel::Receiver<Option<ProcessTable>>;
 pub type CaptureReceiver = single_value_channel::Receiver<Option<Connections>>;
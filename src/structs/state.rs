@@ -0,0 +1,227 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::structs::aggregate::ProcessTraffic;
+use crate::structs::changes::ChangeLog;
+use crate::structs::connection::{Connection, Connections};
+use crate::structs::process::{ExitedProcesses, InodeIndex, ProcessInfo, ProcessInfos, ProcessKey, ProcessTable};
+use crate::structs::rate::add_rates;
+use crate::structs::receivers::{CaptureReceiver, ProcessesReceiver};
+
+pub struct State {
+    pub capture_receiver: CaptureReceiver,
+    pub processes_receiver: ProcessesReceiver,
+    pub connections: Connections,
+    pub processes: ProcessInfos,
+    pub exited: ExitedProcesses,
+    pub inodes: InodeIndex,
+    // The process each connection was last seen bound to, so it stays attributed after the process exits
+    pub bindings: HashMap<String, ProcessKey>,
+    pub changes: ChangeLog,
+    pub process_traffic: ProcessTraffic,
+}
+
+pub type SharedState = Arc<Mutex<State>>;
+
+impl State {
+    pub fn new(capture_receiver: CaptureReceiver, processes_receiver: ProcessesReceiver) -> Self {
+        State {
+            capture_receiver,
+            processes_receiver,
+            connections: Connections::new(),
+            processes: ProcessInfos::new(),
+            exited: ExitedProcesses::new(),
+            inodes: InodeIndex::new(),
+            bindings: HashMap::new(),
+            changes: ChangeLog::new(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64),
+            process_traffic: ProcessTraffic::default(),
+        }
+    }
+
+    // Pulls the latest snapshots from the capture and process threads and binds connections to processes
+    pub fn refresh(&mut self) {
+        if let Some(latest_connections) = self.capture_receiver.latest() {
+            self.connections = latest_connections.clone()
+        }
+
+        // Taken rather than cloned, so requests between two scans do not copy the table again
+        let latest_processes = self.processes_receiver.latest_mut().take();
+        let rescanned = latest_processes.is_some();
+        if let Some(latest_processes) = latest_processes {
+            self.replace_processes(latest_processes);
+        }
+
+        for connection in self.connections.iter_mut() {
+            if connection.bind_matching_process(&self.inodes) {
+                if self.bindings.get(&connection.id) != Some(&connection.process_key()) {
+                    self.bindings.insert(connection.id.clone(), connection.process_key());
+                }
+            } else if let Some(process) = self.bindings.get(&connection.id) {
+                connection.bind(*process);
+            }
+        }
+
+        let (processes, exited) = (&self.processes, &self.exited);
+        self.process_traffic.record(&self.connections, |connection| process_in(processes, exited, connection));
+
+        if rescanned {
+            self.expire_exited_processes();
+        }
+
+        self.changes.record(&self.connections);
+    }
+
+    // Processes missing from the new scan, or whose pid now belongs to another process, have exited
+    fn replace_processes(&mut self, latest_processes: ProcessTable) {
+        for (pid, mut process) in self.processes.drain() {
+            if latest_processes.processes.get(&pid).is_none_or(|latest| latest.start_time != process.start_time) {
+                process.exited = true;
+                self.exited.insert(process.key(), process);
+            }
+        }
+
+        self.processes = latest_processes.processes;
+        self.inodes = latest_processes.inodes;
+    }
+
+    // Exited processes are kept for as long as connections are attributed to them
+    fn expire_exited_processes(&mut self) {
+        let current: HashSet<&str> = self.connections.iter().map(|connection| connection.id.as_str()).collect();
+        self.bindings.retain(|id, _| current.contains(id.as_str()));
+
+        let attributed: HashSet<&ProcessKey> = self.bindings.values().collect();
+        self.exited.retain(|key, _| attributed.contains(key));
+
+        // The traffic of connections that were never bound is kept
+        let (processes, exited) = (&self.processes, &self.exited);
+        self.process_traffic.totals.retain(|key, _| {
+            key.pid == 0 || exited.contains_key(key) || processes.get(&key.pid).is_some_and(|process| process.start_time == key.start_time)
+        });
+    }
+
+    // The process a connection is attributed to, whether it is still running or not
+    pub fn process_of(&self, connection: &Connection) -> Option<&ProcessInfo> {
+        process_in(&self.processes, &self.exited, connection)
+    }
+
+    // Processes with a known executable by pid, the running one when an exited process had the same pid
+    pub fn filtered_processes(&self) -> ProcessInfos {
+        let mut filtered_processes: ProcessInfos = self.exited.values()
+            .chain(self.processes.values())
+            .filter(|process| !process.executable.is_empty())
+            .map(|process| (process.pid, process.clone()))
+            .collect();
+
+        for connection in self.connections.iter() {
+            if let Some(process) = filtered_processes.get_mut(&connection.process_id) {
+                if process.start_time == connection.process_start_time {
+                    add_rates(&mut process.rates, &connection.rates);
+                }
+            }
+        }
+
+        filtered_processes
+    }
+}
+
+fn process_in<'a>(processes: &'a ProcessInfos, exited: &'a ExitedProcesses, connection: &Connection) -> Option<&'a ProcessInfo> {
+    match processes.get(&connection.process_id) {
+        Some(process) if process.start_time == connection.process_start_time => Some(process),
+        _ => exited.get(&connection.process_key()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libc::pid_t;
+
+    use super::State;
+    use crate::helpers::testing::{self, process_table};
+    use crate::structs::connection::Connection;
+    use crate::structs::process::{ProcessInfo, ProcessTable};
+
+    fn connection(local_port: u16, inode: u64) -> Connection {
+        Connection { inode, ..testing::connection(local_port) }
+    }
+
+    fn table(processes: &[(pid_t, u64, &str, Vec<u64>)]) -> ProcessTable {
+        process_table(processes.iter().map(|(pid, start_time, executable, inodes)| ProcessInfo {
+            start_time: *start_time,
+            inodes: inodes.clone(),
+            ..testing::process(*pid, executable)
+        }).collect())
+    }
+
+    #[test]
+    fn keeps_exited_processes_while_connections_are_attributed_to_them() {
+        let (capture_receiver, capture_updater) = single_value_channel::channel();
+        let (processes_receiver, processes_updater) = single_value_channel::channel();
+        let mut state = State::new(capture_receiver, processes_receiver);
+
+        capture_updater.update(Some(vec![connection(1000, 7)])).unwrap();
+        processes_updater.update(Some(table(&[(42, 100, "/usr/bin/curl", vec![7])]))).unwrap();
+        state.refresh();
+        assert_eq!(state.process_of(&state.connections[0]).unwrap().executable, "/usr/bin/curl");
+
+        // The pid now belongs to another program, which must not inherit the connection
+        processes_updater.update(Some(table(&[(42, 500, "/usr/bin/ssh", vec![8])]))).unwrap();
+        state.refresh();
+
+        let exited = state.process_of(&state.connections[0]).unwrap();
+        assert_eq!((exited.executable.as_str(), exited.start_time, exited.exited), ("/usr/bin/curl", 100, true));
+        assert_eq!(state.processes[&42].executable, "/usr/bin/ssh");
+        assert_eq!(state.filtered_processes()[&42].executable, "/usr/bin/ssh");
+
+        // Once the connection is gone so is the process
+        capture_updater.update(Some(vec![connection(1001, 8)])).unwrap();
+        processes_updater.update(Some(table(&[(42, 500, "/usr/bin/ssh", vec![8])]))).unwrap();
+        state.refresh();
+
+        assert!(state.exited.is_empty());
+        assert_eq!(state.process_of(&state.connections[0]).unwrap().start_time, 500);
+    }
+
+    #[test]
+    fn drops_exited_processes_without_connections() {
+        let (capture_receiver, capture_updater) = single_value_channel::channel();
+        let (processes_receiver, processes_updater) = single_value_channel::channel();
+        let mut state = State::new(capture_receiver, processes_receiver);
+
+        capture_updater.update(Some(vec![])).unwrap();
+        processes_updater.update(Some(table(&[(42, 100, "/usr/bin/curl", vec![7]), (43, 100, "/usr/bin/ssh", vec![])]))).unwrap();
+        state.refresh();
+        processes_updater.update(Some(table(&[(43, 100, "/usr/bin/ssh", vec![])]))).unwrap();
+        state.refresh();
+
+        assert!(state.exited.is_empty());
+        assert_eq!(state.processes.len(), 1);
+    }
+
+    #[test]
+    fn counts_process_traffic_after_its_connections_are_evicted() {
+        let (capture_receiver, capture_updater) = single_value_channel::channel();
+        let (processes_receiver, processes_updater) = single_value_channel::channel();
+        let mut state = State::new(capture_receiver, processes_receiver);
+        let traffic = |state: &State, pid| state.process_traffic.totals.iter()
+            .find(|(key, _)| key.pid == pid)
+            .map(|(_, (executable, traffic))| (executable.clone(), traffic.bytes_uploaded));
+
+        capture_updater.update(Some(vec![Connection { bytes_uploaded: 100, ..connection(1000, 7) }])).unwrap();
+        processes_updater.update(Some(table(&[(42, 100, "/usr/bin/curl", vec![7])]))).unwrap();
+        state.refresh();
+        capture_updater.update(Some(vec![Connection { bytes_uploaded: 150, ..connection(1000, 7) }])).unwrap();
+        state.refresh();
+        state.refresh();
+        assert_eq!(traffic(&state, 42), Some(("/usr/bin/curl".to_string(), 150)));
+
+        capture_updater.update(Some(vec![])).unwrap();
+        processes_updater.update(Some(table(&[(42, 100, "/usr/bin/curl", vec![])]))).unwrap();
+        state.refresh();
+        assert_eq!(traffic(&state, 42), Some(("/usr/bin/curl".to_string(), 150)));
+
+        processes_updater.update(Some(table(&[]))).unwrap();
+        state.refresh();
+        assert_eq!(traffic(&state, 42), None);
+    }
+}
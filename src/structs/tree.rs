@@ -0,0 +1,178 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+
+use libc::pid_t;
+use serde_derive::Serialize;
+use utoipa::ToSchema;
+
+use crate::structs::aggregate::TrafficTotals;
+use crate::structs::connection::{Connection, Connections};
+use crate::structs::process::{ProcessInfo, ProcessKey};
+
+#[derive(Clone, Debug, PartialEq, Serialize, ToSchema)]
+pub struct ProcessNode {
+    #[schema(value_type = i32)]
+    pub pid: pid_t,
+    pub start_time: u64,
+    pub exited: bool,
+    pub name: String,
+    pub command: String,
+    pub executable: String,
+    pub user: Option<String>,
+    /// Connections of the process itself
+    pub own: TrafficTotals,
+    /// Connections of the process and all of its descendants
+    pub total: TrafficTotals,
+    /// Ordered by total bytes transferred, most first
+    #[schema(no_recursion)]
+    pub children: Vec<ProcessNode>,
+}
+
+struct TreeBuilder<'a> {
+    processes: BTreeMap<ProcessKey, &'a ProcessInfo>,
+    children: HashMap<ProcessKey, Vec<ProcessKey>>,
+    own: HashMap<ProcessKey, TrafficTotals>,
+    keep_idle: bool,
+}
+
+impl TreeBuilder<'_> {
+    fn node(&self, key: ProcessKey) -> Option<ProcessNode> {
+        let process = self.processes[&key];
+        let own = self.own.get(&key).copied().unwrap_or_default();
+
+        let mut total = own;
+        let mut children: Vec<ProcessNode> = self.children.get(&key).into_iter().flatten()
+            .filter_map(|child| self.node(*child))
+            .collect();
+        for child in &children {
+            total.merge(&child.total);
+        }
+        if !self.keep_idle && total.connections == 0 {
+            return None;
+        }
+        children.sort_by_key(|child| Reverse(child.total.bytes_total()));
+
+        Some(ProcessNode {
+            pid: process.pid,
+            start_time: process.start_time,
+            exited: process.exited,
+            name: process.name.clone(),
+            command: process.command.clone(),
+            executable: process.executable.clone(),
+            user: process.user.clone(),
+            own,
+            total,
+            children,
+        })
+    }
+}
+
+// Parents are found by pid, skipping processes that started after the child and so only reused the parent's pid.
+// Processes started in the same clock tick are ordered by pid, so that two of them cannot be each other's parent.
+// Unless `keep_idle` is set, subtrees without any connection are left out.
+pub fn process_tree<'a, I, F>(processes: I, connections: &Connections, process_of: F, keep_idle: bool) -> Vec<ProcessNode>
+    where I: IntoIterator<Item = &'a ProcessInfo>, F: Fn(&Connection) -> Option<&'a ProcessInfo> {
+    let processes: BTreeMap<ProcessKey, &ProcessInfo> = processes.into_iter().map(|process| (process.key(), process)).collect();
+
+    let mut own: HashMap<ProcessKey, TrafficTotals> = HashMap::new();
+    for connection in connections {
+        if let Some(process) = process_of(connection) {
+            own.entry(process.key()).or_default().add(connection);
+        }
+    }
+
+    let mut by_pid: HashMap<pid_t, Vec<ProcessKey>> = HashMap::new();
+    for key in processes.keys() {
+        by_pid.entry(key.pid).or_default().push(*key);
+    }
+
+    let mut roots = Vec::new();
+    let mut children: HashMap<ProcessKey, Vec<ProcessKey>> = HashMap::new();
+    for (key, process) in &processes {
+        let parent = by_pid.get(&process.parent_pid).into_iter().flatten()
+            .filter(|parent| (parent.start_time, parent.pid) < (key.start_time, key.pid))
+            .max_by_key(|parent| parent.start_time);
+
+        match parent {
+            Some(parent) => children.entry(*parent).or_default().push(*key),
+            None => roots.push(*key),
+        }
+    }
+
+    let builder = TreeBuilder { processes, children, own, keep_idle };
+    let mut nodes: Vec<ProcessNode> = roots.into_iter().filter_map(|root| builder.node(root)).collect();
+    nodes.sort_by_key(|node| Reverse(node.total.bytes_total()));
+
+    nodes
+}
+
+#[cfg(test)]
+mod tests {
+    use libc::pid_t;
+
+    use super::process_tree;
+    use crate::helpers::testing::{self, by_pid};
+    use crate::structs::connection::Connection;
+    use crate::structs::process::{ProcessInfo, ProcessInfos};
+
+    fn process(pid: pid_t, parent_pid: pid_t, start_time: u64, name: &str) -> ProcessInfo {
+        ProcessInfo { parent_pid, start_time, name: name.to_string(), ..testing::process(pid, name) }
+    }
+
+    fn connection(local_port: u16, process_id: pid_t, bytes_downloaded: usize) -> Connection {
+        Connection { process_id, bytes_downloaded, ..testing::connection(local_port) }
+    }
+
+    #[test]
+    fn rolls_traffic_up_to_ancestors() {
+        let processes: ProcessInfos = by_pid(vec![
+            process(1, 0, 1, "init"),
+            process(100, 1, 10, "bash"),
+            process(200, 100, 20, "cargo"),
+            process(201, 200, 21, "rustc"),
+            process(202, 200, 22, "curl"),
+            process(300, 1, 30, "sshd"),
+            // Started before its parent's pid was taken, so it belonged to an earlier process
+            process(400, 300, 5, "orphan"),
+        ]);
+        let connections = vec![
+            connection(1000, 200, 10),
+            connection(1001, 202, 2_000),
+            connection(1002, 202, 500),
+            connection(1003, 300, 40),
+            connection(1004, 999, 1_000_000),
+        ];
+
+        let tree = process_tree(processes.values(), &connections, |connection| processes.get(&connection.process_id), false);
+
+        assert_eq!(tree.len(), 1);
+        let init = &tree[0];
+        assert_eq!((init.name.as_str(), init.own.connections, init.total.bytes_downloaded), ("init", 0, 2_550));
+
+        let names: Vec<&str> = init.children.iter().map(|child| child.name.as_str()).collect();
+        assert_eq!(names, vec!["bash", "sshd"]);
+
+        let cargo = &init.children[0].children[0];
+        assert_eq!((cargo.own.bytes_downloaded, cargo.total.bytes_downloaded, cargo.total.connections), (10, 2_510, 3));
+        assert_eq!(cargo.children.len(), 1);
+        assert_eq!(cargo.children[0].name, "curl");
+
+        let everything = process_tree(processes.values(), &connections, |connection| processes.get(&connection.process_id), true);
+        let roots: Vec<pid_t> = everything.iter().map(|node| node.pid).collect();
+        assert_eq!(roots, vec![1, 400]);
+        assert_eq!(everything[0].children[0].children[0].children.len(), 2);
+    }
+
+    #[test]
+    fn breaks_parent_cycles_between_processes_started_together() {
+        // Each one's parent pid was reused by the other within the same clock tick
+        let processes: ProcessInfos = by_pid(vec![process(500, 501, 70, "first"), process(501, 500, 70, "second")]);
+        let connections = vec![connection(1000, 501, 10)];
+
+        let tree = process_tree(processes.values(), &connections, |connection| processes.get(&connection.process_id), false);
+
+        assert_eq!(tree.len(), 1);
+        assert_eq!((tree[0].pid, tree[0].total.bytes_downloaded), (500, 10));
+        assert_eq!(tree[0].children[0].pid, 501);
+    }
+}
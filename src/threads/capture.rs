@@ -1,105 +1,289 @@
+use std::collections::HashSet;
+use std::fmt;
 use std::net::{IpAddr, SocketAddr};
 use std::sync::{Arc, Mutex};
 use std::{thread};
+use std::path::Path;
 use std::thread::JoinHandle;
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use etherparse::InternetSlice::Ipv4;
-use etherparse::InternetSlice::Ipv6;
-use etherparse::SlicedPacket;
+use etherparse::LaxNetSlice::Ipv4;
+use etherparse::LaxNetSlice::Ipv6;
+use etherparse::{EtherType, LaxSlicedPacket};
 use etherparse::TransportSlice::{Tcp, Udp};
-use pcap::{Device, Linktype, Packet};
+use pcap::{Activated, Capture, Device, Linktype, Offline, Packet, PacketHeader};
 use single_value_channel;
 use crate::helpers::debug::is_debug;
 use crate::helpers::display::print_devices;
 
-use crate::structs::connection::{Connection, Connections, TransportType};
+use crate::structs::config::{ByteAccounting, Config, ReplayMode};
+use crate::structs::connection::{Connection, Connections, FlowTable, TransportType};
+use crate::structs::health::{CaptureHealth, SharedHealth};
 use crate::structs::receivers::{CaptureReceiver, ConnectionsReceiver};
 
+const LINUX_SLL_HEADER_LEN: usize = 16;
+const LINUX_SLL_PROTOCOL_OFFSET: usize = 14;
+const LINUX_SLL2_HEADER_LEN: usize = 20;
+const LINUX_SLL2_PROTOCOL_OFFSET: usize = 0;
+
 pub type CaptureUpdater = single_value_channel::Updater<Option<Connections>>;
 
+// Flows captured on the monitored devices are matched with the sockets in /proc/net, replayed ones belong to no
+// process on this host
+pub enum CaptureSource {
+    Devices(ConnectionsReceiver),
+    File(Capture<Offline>),
+}
+
 pub enum Direction {
     Incoming,
     Outgoing,
 }
 
-pub fn run(connections_thread: ConnectionsReceiver, device_name: &Option<String>) -> (Vec<JoinHandle<()>>, CaptureReceiver) {
+// Packets that parsed fine but carry no traffic to account are ignored rather than counted as parse errors
+#[derive(Debug)]
+pub enum PacketError {
+    Ignored(&'static str),
+    Invalid(String),
+}
+
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PacketError::Ignored(reason) => write!(f, "Ignored packet: {}", reason),
+            PacketError::Invalid(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PacketSize {
+    pub wire: usize,
+    pub payload: usize,
+}
+
+impl PacketSize {
+    pub fn accounted(&self, accounting: ByteAccounting) -> usize {
+        match accounting {
+            ByteAccounting::Wire => self.wire,
+            ByteAccounting::Payload => self.payload,
+        }
+    }
+}
+
+pub fn run(interval: u64, source: CaptureSource, config: &Config, health: SharedHealth) -> (Vec<JoinHandle<()>>, CaptureReceiver) {
     let (receiver, updater) = single_value_channel::channel();
 
-    let devices = Device::list().unwrap().into_iter().filter(|device|{
-        !device.flags.is_loopback()
-            && device.flags.is_up()
-            && device.flags.is_running()
-            && device.name != "any"
-            && (device_name.is_none() || device.name == *device_name.as_ref().unwrap())
-    }).collect();
+    let flow_table_mutex = Arc::new(Mutex::new(FlowTable::new()));
+
+    let (mut connections_receiver, replay) = match source {
+        CaptureSource::Devices(connections_receiver) => (Some(connections_receiver), None),
+        CaptureSource::File(capture) => (None, Some(capture)),
+    };
+
+    let publisher_flow_table = flow_table_mutex.clone();
+    let publisher_config = config.clone();
+    let publisher_handle = thread::spawn(move || loop {
+        publish_snapshot(&publisher_flow_table, connections_receiver.as_mut(), &updater, &publisher_config);
+        thread::sleep(Duration::from_millis(interval));
+    });
+
+    if let Some(capture) = replay {
+        let capture_file = config.capture_file.clone().unwrap_or_default();
+        println!("Started replay thread for {}", capture_file.display());
+
+        let replay_config = config.clone();
+
+        let handle = thread::spawn(move || {
+            let _alive = health.alive();
+            let packet_count = replay_file(capture, &replay_config, &flow_table_mutex, &health);
+            println!("Finished replaying {} packets from {}", packet_count, capture_file.display());
+        });
+
+        return (vec![publisher_handle, handle], receiver);
+    }
+
+    let all_devices = Device::list().unwrap();
+    let devices = all_devices.iter()
+        .filter(|device| is_monitored(device, &config.device_name))
+        .cloned()
+        .collect();
     print_devices(&devices);
 
-    let connections_mutex = Arc::new(Mutex::new(Connections::new()));
-    let receiver_mutex = Arc::new(Mutex::new(connections_thread));
-    let updater_mutex = Arc::new(Mutex::new(updater));
-    let handles: Vec<JoinHandle<()>> = devices.into_iter()
+    let mut handles: Vec<JoinHandle<()>> = devices.into_iter()
         .map(|device| {
-        let connections_mutex_instance = connections_mutex.clone();
-        let receiver_mutex_instance = receiver_mutex.clone();
-        let updater = updater_mutex.clone();
+        let addresses = local_addresses(&device, &all_devices, &config.local_addresses);
+        let flow_table_mutex_instance = flow_table_mutex.clone();
+        let device_config = config.clone();
+        let device_health = health.clone();
 
-        thread::spawn(move ||
-            monitor_device(device, &connections_mutex_instance, &receiver_mutex_instance, &updater)
-        )
+        thread::spawn(move || {
+            let _alive = device_health.alive();
+            monitor_device(device, addresses, &device_config, &flow_table_mutex_instance, &device_health)
+        })
     }).collect();
 
     println!("Started {} capture threads", handles.len());
 
+    handles.push(publisher_handle);
+
     (handles, receiver)
 }
 
-fn monitor_device(device: Device, connections_mutex: &Mutex<Connections>, receiver_mutex: &Mutex<ConnectionsReceiver>, updater_mutex: &Mutex<CaptureUpdater>) {
-    let addresses = device.addresses.iter().map(|address| address.addr).collect::<Vec<IpAddr>>();
-    let mut cap = device.open().expect("Failed to load device");
+pub fn is_monitored(device: &Device, device_name: &Option<String>) -> bool {
+    !device.flags.is_loopback()
+        && device.flags.is_up()
+        && device.flags.is_running()
+        // Capturing on every device and the any pseudo-device at once would count each packet twice
+        && (device.name != "any" || device_name.as_deref() == Some("any"))
+        && (device_name.is_none() || device.name == *device_name.as_ref().unwrap())
+}
+
+// The any pseudo-device has no addresses of its own but sees the traffic of every interface. Addresses given with
+// --local-address count as local on any device.
+fn local_addresses(device: &Device, all_devices: &[Device], extra_addresses: &[IpAddr]) -> Vec<IpAddr> {
+    let devices = if device.name == "any" { all_devices } else { std::slice::from_ref(device) };
+
+    devices.iter()
+        .flat_map(|device| device.addresses.iter().map(|address| address.addr))
+        .chain(extra_addresses.iter().copied())
+        .collect()
+}
+
+fn monitor_device(device: Device, addresses: Vec<IpAddr>, config: &Config, flow_table_mutex: &Mutex<FlowTable>, health: &CaptureHealth) {
+    let device_name = device.name.clone();
+    let mut cap = match Capture::from_device(device).and_then(|cap| cap.snaplen(config.snaplen).open()) {
+        Ok(cap) => cap,
+        Err(error) => {
+            eprintln!("Error: Failed to open device {}: {}", device_name, error);
+            return;
+        }
+    };
+    if let Err(error) = apply_filter(&mut cap, &config.filter) {
+        eprintln!("Error: Invalid filter for device {}: {}", device_name, error);
+        return;
+    }
     let link_type = cap.get_datalink();
 
     while let Ok(packet) = cap.next_packet() {
-        {
-            let mut connections = connections_mutex.lock().unwrap();
-            let mut receiver = receiver_mutex.lock().unwrap();
-            update_connections_with_inodes_from_receiver(&mut connections, &mut receiver);
-        }
+        handle_packet(packet, link_type, &addresses, config, flow_table_mutex, health);
+    }
+}
+
+// Opened before the capture threads start, so a missing or corrupt file stops crystalline instead of a thread
+pub fn open_file(path: &Path, filter: &Option<String>) -> Result<Capture<Offline>, String> {
+    let mut cap = Capture::from_file(path)
+        .map_err(|error| format!("Failed to open capture file {}: {}", path.display(), error))?;
+    apply_filter(&mut cap, filter)
+        .map_err(|error| format!("Invalid filter for {}: {}", path.display(), error))?;
+
+    Ok(cap)
+}
+
+fn replay_file(mut cap: Capture<Offline>, config: &Config, flow_table_mutex: &Mutex<FlowTable>, health: &CaptureHealth) -> usize {
+    let link_type = cap.get_datalink();
 
-        match process_packet(packet, link_type, &addresses) {
-            Err(error) => if is_debug() { println!("Error: {}", error) },
-            Ok((connection, bytes_transferred, direction)) => {
-                let mut connections = connections_mutex.lock().unwrap();
-                let updater = updater_mutex.lock().unwrap();
+    let mut replay_start: Option<(Instant, Duration)> = None;
+    let mut packet_count = 0;
 
-                update_connections_with_bytes_transferred(&mut connections, connection, bytes_transferred, direction);
-                updater.update(Some(connections.clone())).unwrap();
+    while let Ok(packet) = cap.next_packet() {
+        if config.replay_mode == ReplayMode::RealTime {
+            let packet_offset = packet_timestamp(packet.header).duration_since(UNIX_EPOCH).unwrap_or_default();
+            let (started_at, first_offset) = *replay_start.get_or_insert((Instant::now(), packet_offset));
+            let target = packet_offset.saturating_sub(first_offset);
+
+            if let Some(remaining) = target.checked_sub(started_at.elapsed()) {
+                thread::sleep(remaining);
             }
-        };
+        }
+
+        handle_packet(packet, link_type, &config.local_addresses, config, flow_table_mutex, health);
+        packet_count += 1;
     }
+
+    packet_count
 }
 
-fn process_packet(packet: Packet, link_type: Linktype, addresses: &Vec<IpAddr>) -> Result<(Connection, usize, Direction), String> {
-    // Parse packet
+fn apply_filter<T: Activated + ?Sized>(cap: &mut Capture<T>, filter: &Option<String>) -> Result<(), pcap::Error> {
+    match filter {
+        Some(program) => cap.filter(program, true),
+        None => Ok(()),
+    }
+}
+
+fn handle_packet(packet: Packet, link_type: Linktype, addresses: &Vec<IpAddr>, config: &Config, flow_table_mutex: &Mutex<FlowTable>, health: &CaptureHealth) {
+    let result = process_packet(packet, link_type, addresses);
+    match &result {
+        Err(PacketError::Ignored(_)) => health.record_ignored(),
+        _ => health.record_packet(result.is_ok()),
+    }
+
+    match result {
+        Err(error) => if is_debug() { println!("Error: {}", error) },
+        Ok((connection, packet_size, direction)) => {
+            let (remote, uploaded) = match direction {
+                Direction::Outgoing => (connection.destination.ip(), true),
+                Direction::Incoming => (connection.source.ip(), false),
+            };
+            health.record_remote(remote, packet_size.accounted(config.accounting), uploaded);
+
+            let mut flow_table = flow_table_mutex.lock().unwrap();
+            update_connections_with_bytes_transferred(&mut flow_table, connection, packet_size, direction, config);
+        }
+    };
+}
+
+fn publish_snapshot(flow_table_mutex: &Mutex<FlowTable>, receiver: Option<&mut ConnectionsReceiver>, updater: &CaptureUpdater, config: &Config) {
+    let mut connections: Connections = {
+        let mut flow_table = flow_table_mutex.lock().unwrap();
+        if let Some(receiver) = receiver {
+            update_connections_with_inodes_from_receiver(&mut flow_table, receiver);
+            evict_idle_flows(&mut flow_table, receiver.latest().as_deref().unwrap_or_default(), config.flow_idle_timeout(), SystemTime::now());
+        }
+        flow_table.iter().cloned().collect()
+    };
+
+    let now = current_second();
+    for connection in connections.iter_mut() {
+        connection.rates = connection.rate_meter.rates(now, &config.rate_windows);
+    }
+
+    connections.sort_unstable_by(|a, b| b.cmp(a));
+    updater.update(Some(connections)).unwrap();
+}
+
+fn current_second() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+fn packet_timestamp(header: &PacketHeader) -> SystemTime {
+    UNIX_EPOCH + Duration::new(header.ts.tv_sec as u64, header.ts.tv_usec as u32 * 1000)
+}
+
+fn process_packet(packet: Packet, link_type: Linktype, addresses: &Vec<IpAddr>) -> Result<(Connection, PacketSize, Direction), PacketError> {
+    // Parse packet, tolerating payloads cut short by the snap length
     let packet_parse_result = match link_type {
-        Linktype(12) | Linktype::NULL => SlicedPacket::from_ip(&packet),
-        Linktype::ETHERNET => SlicedPacket::from_ethernet(&packet),
-        _ => return Err(format!("Unsupported link type {:?}", link_type.get_description()))
+        Linktype(12) | Linktype::NULL | Linktype::RAW | Linktype::IPV4 | Linktype::IPV6 => LaxSlicedPacket::from_ip(&packet).map_err(|error| format!("{:?}", error)),
+        Linktype::ETHERNET => LaxSlicedPacket::from_ethernet(&packet).map_err(|error| format!("{:?}", error)),
+        Linktype::LINUX_SLL => slice_cooked_packet(&packet, LINUX_SLL_HEADER_LEN, LINUX_SLL_PROTOCOL_OFFSET),
+        Linktype::LINUX_SLL2 => slice_cooked_packet(&packet, LINUX_SLL2_HEADER_LEN, LINUX_SLL2_PROTOCOL_OFFSET),
+        _ => return Err(PacketError::Invalid(format!("Unsupported link type {:?}", link_type.get_description())))
     };
     if let Err(error) = packet_parse_result {
-        return Err(format!("Error in parsing packet: {:?}", error));
+        return Err(PacketError::Invalid(format!("Error in parsing packet: {}", error)));
     }
     let packet_data = packet_parse_result.unwrap();
+    let seen_at = packet_timestamp(packet.header);
 
 
-    // Get source and destination IPs
-    if packet_data.net.is_none() {
-        return Err("Received non-ip packet".to_string());
-    }
-
-    // Get transport type
-    if packet_data.transport.is_none() {
-        return Err("Received non-tcp/udp packet".to_string());
+    // Get source and destination IPs and transport type. A header that failed to parse is an error, a protocol we do
+    // not account is not.
+    if packet_data.net.is_none() || packet_data.transport.is_none() {
+        return Err(match (packet_data.stop_err, packet_data.net.is_none()) {
+            (Some((error, layer)), _) => PacketError::Invalid(format!("Error in parsing packet at {:?}: {:?}", layer, error)),
+            (None, true) => PacketError::Ignored("Received non-ip packet"),
+            (None, false) => PacketError::Ignored("Received non-tcp/udp packet"),
+        });
     }
 
     let (source_ip, destination_ip) = match packet_data.net.unwrap() {
@@ -107,75 +291,502 @@ fn process_packet(packet: Packet, link_type: Linktype, addresses: &Vec<IpAddr>)
         Ipv6(slice) => (IpAddr::V6(slice.header().source_addr()), IpAddr::V6(slice.header().destination_addr()))
     };
 
-    let connection = match packet_data.transport.unwrap() {
-        Tcp(header) => Connection {
-            source: SocketAddr::new(source_ip, header.source_port()),
-            destination: SocketAddr::new(destination_ip, header.destination_port()),
-            inode: 0,
-            process_id: 0,
-            transport_type: TransportType::Tcp,
-            bytes_uploaded: 0,
-            bytes_downloaded: 0,
-            first_seen: SystemTime::now(),
-            last_seen: SystemTime::now(),
-        },
-        Udp(header) => Connection {
-            source: SocketAddr::new(source_ip, header.source_port()),
-            destination: SocketAddr::new(destination_ip, header.destination_port()),
-            inode: 0,
-            process_id: 0,
-            transport_type: TransportType::Udp,
-            bytes_uploaded: 0,
-            bytes_downloaded: 0,
-            first_seen: SystemTime::now(),
-            last_seen: SystemTime::now(),
-        },
-        _ => return Err("Received non-tcp/udp packet".to_string())
+    let (source_port, destination_port, transport_type, payload) = match packet_data.transport.unwrap() {
+        Tcp(header) => (header.source_port(), header.destination_port(), TransportType::Tcp, header.payload()),
+        Udp(header) => (header.source_port(), header.destination_port(), TransportType::Udp, header.payload()),
+        _ => return Err(PacketError::Ignored("Received non-tcp/udp packet"))
     };
+    let connection = Connection::unidentified(
+        SocketAddr::new(source_ip, source_port),
+        SocketAddr::new(destination_ip, destination_port),
+        transport_type,
+        seen_at,
+    );
 
-    let packet_size = packet.len();
+    // Count the original packet length, which is larger than what was captured when the snap length cut it short
+    let original_len = (packet.header.len as usize).max(packet.len());
+    let payload_size = if original_len > packet.len() {
+        let headers_len = payload.as_ptr() as usize - packet.data.as_ptr() as usize;
+        original_len - headers_len
+    } else {
+        payload.len()
+    };
+    let packet_size = PacketSize {
+        wire: original_len,
+        payload: payload_size,
+    };
 
     let direction = match addresses {
         _ if addresses.contains(&source_ip) => Direction::Outgoing,
         _ if addresses.contains(&destination_ip) => Direction::Incoming,
-        _ => return Err("Packet not from or to monitored device".to_string())
+        _ => return Err(PacketError::Ignored("Packet not from or to monitored device"))
     };
 
     Ok((connection, packet_size, direction))
 }
 
-fn update_connections_with_inodes_from_receiver(connections: &mut Connections, receiver: &mut ConnectionsReceiver) {
+fn slice_cooked_packet(data: &[u8], header_len: usize, protocol_offset: usize) -> Result<LaxSlicedPacket<'_>, String> {
+    if data.len() < header_len {
+        return Err("Received truncated cooked capture header".to_string());
+    }
+    let protocol_type = EtherType(u16::from_be_bytes([data[protocol_offset], data[protocol_offset + 1]]));
+
+    Ok(LaxSlicedPacket::from_ether_type(protocol_type, &data[header_len..]))
+}
+
+fn update_connections_with_inodes_from_receiver(flow_table: &mut FlowTable, receiver: &mut ConnectionsReceiver) {
     let new_connections = match receiver.latest() {
         Some(connections) => connections,
         None => return
     };
 
     for new_connection in new_connections {
-        let find_connection_result = connections.iter_mut().find(|current| **current == *new_connection);
-        if let Some(found_connection) = find_connection_result {
-            if found_connection.inode == 0 {
-                found_connection.inode = new_connection.inode;
+        match flow_table.get(new_connection) {
+            Some(found_connection) if found_connection.inode == 0 => {
+                let mut updated_connection = found_connection.clone();
+                updated_connection.inode = new_connection.inode;
+                flow_table.replace(updated_connection);
+            }
+            Some(_) => {}
+            None => {
+                flow_table.insert(new_connection.clone());
             }
-        } else {
-            connections.push(new_connection.clone());
         }
     }
 }
 
-fn update_connections_with_bytes_transferred(connections: &mut Connections, connection: Connection, bytes_transferred: usize, direction: Direction) {
-    if let Some(found_connection) = connections.iter_mut().find(|current| **current == connection) {
-        match direction {
-            Direction::Outgoing => found_connection.bytes_uploaded += bytes_transferred,
-            Direction::Incoming => found_connection.bytes_downloaded += bytes_transferred,
+// Like the sockets read from /proc/net, flows are dropped once idle for a while unless their socket is still open.
+// Replayed flows are kept, as they were last seen when the file was recorded.
+fn evict_idle_flows(flow_table: &mut FlowTable, open_sockets: &[Connection], idle_timeout: Duration, now: SystemTime) {
+    let open_sockets: HashSet<&Connection> = open_sockets.iter().collect();
+
+    flow_table.retain(|flow| {
+        open_sockets.contains(flow) || now.duration_since(flow.last_seen).unwrap_or_default() <= idle_timeout
+    });
+}
+
+fn update_connections_with_bytes_transferred(flow_table: &mut FlowTable, connection: Connection, packet_size: PacketSize, direction: Direction, config: &Config) {
+    let last_seen = connection.last_seen;
+    let bytes_transferred = packet_size.accounted(config.accounting);
+    let mut flow = match flow_table.take(&connection) {
+        Some(flow) => flow,
+        None => {
+            // Keep the local endpoint as the source, like the entries read from /proc/net
+            let mut flow = match direction {
+                Direction::Incoming => Connection { source: connection.destination, destination: connection.source, ..connection },
+                Direction::Outgoing => connection,
+            };
+            flow.identify();
+            flow
         }
-    } else {
-        let mut new_connection = connection.clone();
-        match direction {
-            Direction::Outgoing => new_connection.bytes_uploaded += bytes_transferred,
-            Direction::Incoming => new_connection.bytes_downloaded += bytes_transferred,
+    };
+
+    flow.last_seen = last_seen;
+    match direction {
+        Direction::Outgoing => {
+            flow.bytes_uploaded += bytes_transferred;
+            flow.packets_uploaded += 1;
+            flow.wire_bytes_uploaded += packet_size.wire;
+            flow.payload_bytes_uploaded += packet_size.payload;
+            flow.rate_meter.record(current_second(), bytes_transferred, 0, config.rate_horizon());
+        }
+        Direction::Incoming => {
+            flow.bytes_downloaded += bytes_transferred;
+            flow.packets_downloaded += 1;
+            flow.wire_bytes_downloaded += packet_size.wire;
+            flow.payload_bytes_downloaded += packet_size.payload;
+            flow.rate_meter.record(current_second(), 0, bytes_transferred, config.rate_horizon());
         }
-        connections.push(new_connection);
     }
 
-    connections.sort_unstable_by(|a, b| b.cmp(a));
+    flow_table.insert(flow);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::path::Path;
+    use std::sync::Mutex;
+    use std::sync::atomic::Ordering;
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+
+    use pcap::{Address, Device, DeviceFlags, IfFlags, Linktype, Packet, PacketHeader};
+
+    use super::{evict_idle_flows, is_monitored, local_addresses, open_file, process_packet, replay_file, update_connections_with_bytes_transferred, Direction, PacketError, PacketSize};
+    use crate::structs::config::{ByteAccounting, Config};
+    use crate::structs::connection::{Connection, FlowTable, TransportType};
+    use crate::structs::health::CaptureHealth;
+
+    const LOCAL: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 10);
+    const REMOTE: Ipv4Addr = Ipv4Addr::new(93, 184, 216, 34);
+
+    // IPv4 + UDP from 192.168.1.10:5353 to 93.184.216.34:53 with a 4 byte payload
+    fn ipv4_udp() -> Vec<u8> {
+        vec![
+            0x45, 0x00, 0x00, 0x20, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00,
+            192, 168, 1, 10,
+            93, 184, 216, 34,
+            0x14, 0xe9, 0x00, 0x35, 0x00, 0x0c, 0x00, 0x00,
+            0xde, 0xad, 0xbe, 0xef,
+        ]
+    }
+
+    // The same datagram sent back from 93.184.216.34:53 to 192.168.1.10:5353
+    fn ipv4_udp_reply() -> Vec<u8> {
+        let mut data = ipv4_udp();
+        data[12..20].rotate_left(4);
+        data[20..24].rotate_left(2);
+        data
+    }
+
+    fn ethernet_header(ether_type: u16) -> Vec<u8> {
+        let mut header = vec![0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb];
+        header.extend_from_slice(&ether_type.to_be_bytes());
+        header
+    }
+
+    fn process(data: &[u8], link_type: Linktype, local: IpAddr) -> Result<(Connection, PacketSize, Direction), PacketError> {
+        let header = PacketHeader {
+            ts: libc::timeval { tv_sec: 0, tv_usec: 0 },
+            caplen: data.len() as u32,
+            len: data.len() as u32,
+        };
+
+        process_packet(Packet::new(&header, data), link_type, &vec![local])
+    }
+
+    fn assert_outgoing_udp(data: &[u8], link_type: Linktype) {
+        let (connection, size, direction) = process(data, link_type, IpAddr::V4(LOCAL)).unwrap();
+
+        assert_eq!(connection.source.ip(), IpAddr::V4(LOCAL));
+        assert_eq!(connection.destination.ip(), IpAddr::V4(REMOTE));
+        assert_eq!(connection.transport_type, TransportType::Udp);
+        assert_eq!(size, PacketSize { wire: data.len(), payload: 4 });
+        assert!(matches!(direction, Direction::Outgoing));
+    }
+
+    #[test]
+    fn parses_ethernet() {
+        let mut data = ethernet_header(0x0800);
+        data.extend(ipv4_udp());
+
+        assert_outgoing_udp(&data, Linktype::ETHERNET);
+    }
+
+    #[test]
+    fn parses_vlan_tagged_ethernet() {
+        let mut data = ethernet_header(0x8100);
+        data.extend_from_slice(&[0x00, 0x2a, 0x08, 0x00]);
+        data.extend(ipv4_udp());
+
+        assert_outgoing_udp(&data, Linktype::ETHERNET);
+    }
+
+    #[test]
+    fn parses_double_vlan_tagged_ethernet() {
+        let mut data = ethernet_header(0x88a8);
+        data.extend_from_slice(&[0x00, 0x0a, 0x81, 0x00, 0x00, 0x2a, 0x08, 0x00]);
+        data.extend(ipv4_udp());
+
+        assert_outgoing_udp(&data, Linktype::ETHERNET);
+    }
+
+    #[test]
+    fn parses_raw_ip() {
+        let data = ipv4_udp();
+
+        assert_outgoing_udp(&data, Linktype::RAW);
+        assert_outgoing_udp(&data, Linktype::IPV4);
+    }
+
+    #[test]
+    fn parses_raw_ipv6() {
+        let mut data = vec![0x60, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x11, 0x40];
+        data.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01]);
+        data.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x02]);
+        data.extend_from_slice(&[0x14, 0xe9, 0x00, 0x35, 0x00, 0x0c, 0x00, 0x00, 0xde, 0xad, 0xbe, 0xef]);
+
+        let local: IpAddr = "2001:db8::2".parse().unwrap();
+        let (connection, _, direction) = process(&data, Linktype::IPV6, local).unwrap();
+
+        assert_eq!(connection.destination.ip(), local);
+        assert_eq!(connection.destination.port(), 53);
+        assert!(matches!(direction, Direction::Incoming));
+    }
+
+    #[test]
+    fn parses_linux_sll() {
+        let mut data = vec![
+            0x00, 0x04, // packet type: outgoing
+            0x00, 0x01, // ARPHRD_ETHER
+            0x00, 0x06, // address length
+            0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x00, 0x00,
+            0x08, 0x00, // protocol type: IPv4
+        ];
+        data.extend(ipv4_udp());
+
+        assert_outgoing_udp(&data, Linktype::LINUX_SLL);
+    }
+
+    #[test]
+    fn parses_linux_sll2() {
+        let mut data = vec![
+            0x08, 0x00, // protocol type: IPv4
+            0x00, 0x00, // reserved
+            0x00, 0x00, 0x00, 0x02, // interface index
+            0x00, 0x01, // ARPHRD_ETHER
+            0x04, // packet type: outgoing
+            0x06, // address length
+            0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x00, 0x00,
+        ];
+        data.extend(ipv4_udp());
+
+        assert_outgoing_udp(&data, Linktype::LINUX_SLL2);
+    }
+
+    #[test]
+    fn counts_original_length_of_truncated_udp() {
+        let mut data = ethernet_header(0x0800);
+        data.extend(ipv4_udp());
+        data[14 + 3] = 20 + 8 + 100;
+        data[14 + 20 + 5] = 8 + 100;
+        let original_len = data.len() - 4 + 100;
+
+        let header = PacketHeader {
+            ts: libc::timeval { tv_sec: 0, tv_usec: 0 },
+            caplen: data.len() as u32,
+            len: original_len as u32,
+        };
+        let (connection, size, _) = process_packet(Packet::new(&header, &data), Linktype::ETHERNET, &vec![IpAddr::V4(LOCAL)]).unwrap();
+
+        assert_eq!(connection.destination.port(), 53);
+        assert_eq!(size, PacketSize { wire: original_len, payload: 100 });
+    }
+
+    #[test]
+    fn counts_original_length_of_truncated_tcp() {
+        let mut data = vec![
+            0x45, 0x00, 0x05, 0xdc, 0x00, 0x00, 0x40, 0x00, 0x40, 0x06, 0x00, 0x00,
+            192, 168, 1, 10,
+            93, 184, 216, 34,
+            0xc3, 0x50, 0x01, 0xbb, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+            0x50, 0x18, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00,
+        ];
+        data.extend_from_slice(&[0xaa; 24]);
+
+        let header = PacketHeader {
+            ts: libc::timeval { tv_sec: 0, tv_usec: 0 },
+            caplen: data.len() as u32,
+            len: 1500,
+        };
+        let (connection, size, direction) = process_packet(Packet::new(&header, &data), Linktype::RAW, &vec![IpAddr::V4(LOCAL)]).unwrap();
+
+        assert_eq!(connection.transport_type, TransportType::Tcp);
+        assert_eq!(connection.destination.port(), 443);
+        assert_eq!(size, PacketSize { wire: 1500, payload: 1460 });
+        assert!(matches!(direction, Direction::Outgoing));
+    }
+
+    #[test]
+    fn rejects_truncated_linux_sll2() {
+        assert!(matches!(process(&[0x08, 0x00, 0x00], Linktype::LINUX_SLL2, IpAddr::V4(LOCAL)), Err(PacketError::Invalid(_))));
+    }
+
+    #[test]
+    fn rejects_unsupported_link_type() {
+        assert!(matches!(process(&ipv4_udp(), Linktype::IEEE802_11, IpAddr::V4(LOCAL)), Err(PacketError::Invalid(_))));
+    }
+
+    #[test]
+    fn ignores_traffic_it_does_not_account() {
+        let mut arp = ethernet_header(0x0806);
+        arp.extend_from_slice(&[0; 28]);
+        assert!(matches!(process(&arp, Linktype::ETHERNET, IpAddr::V4(LOCAL)), Err(PacketError::Ignored(_))));
+
+        let mut icmp = ipv4_udp();
+        icmp[9] = 1;
+        assert!(matches!(process(&icmp, Linktype::RAW, IpAddr::V4(LOCAL)), Err(PacketError::Ignored(_))));
+
+        let elsewhere = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        assert!(matches!(process(&ipv4_udp(), Linktype::RAW, elsewhere), Err(PacketError::Ignored(_))));
+
+        let mut truncated_udp = ipv4_udp();
+        truncated_udp.truncate(24);
+        assert!(matches!(process(&truncated_udp, Linktype::RAW, IpAddr::V4(LOCAL)), Err(PacketError::Invalid(_))));
+    }
+
+    #[test]
+    fn merges_both_directions_into_one_flow() {
+        let mut flow_table = FlowTable::new();
+        let (outgoing, outgoing_size, outgoing_direction) = process(&ipv4_udp(), Linktype::RAW, IpAddr::V4(LOCAL)).unwrap();
+        let (incoming, incoming_size, incoming_direction) = process(&ipv4_udp_reply(), Linktype::RAW, IpAddr::V4(LOCAL)).unwrap();
+
+        let config = Config::default();
+        assert!(outgoing.id.is_empty() && incoming.id.is_empty());
+
+        update_connections_with_bytes_transferred(&mut flow_table, incoming, incoming_size, incoming_direction, &config);
+        update_connections_with_bytes_transferred(&mut flow_table, outgoing.clone(), outgoing_size, outgoing_direction, &config);
+        update_connections_with_bytes_transferred(&mut flow_table, outgoing, outgoing_size, Direction::Outgoing, &config);
+
+        assert_eq!(flow_table.len(), 1);
+        let flow = flow_table.iter().next().unwrap();
+        assert_eq!(flow.bytes_uploaded, 2 * outgoing_size.wire);
+        assert_eq!(flow.bytes_downloaded, incoming_size.wire);
+        assert_eq!(flow.source.ip(), IpAddr::V4(LOCAL));
+        assert_eq!(flow.packets_uploaded, 2);
+        assert_eq!(flow.packets_downloaded, 1);
+        assert_eq!(flow.id, Connection::new(flow.source, flow.destination, TransportType::Udp, flow.first_seen).id);
+    }
+
+    fn device(name: &str, addresses: &[&str]) -> Device {
+        Device {
+            name: name.to_string(),
+            desc: None,
+            addresses: addresses.iter().map(|address| Address {
+                addr: address.parse().unwrap(),
+                netmask: None,
+                broadcast_addr: None,
+                dst_addr: None,
+            }).collect(),
+            flags: DeviceFlags::from((IfFlags::UP | IfFlags::RUNNING).bits()),
+        }
+    }
+
+    #[test]
+    fn monitors_any_only_when_requested() {
+        let any = device("any", &[]);
+        let eth0 = device("eth0", &["192.168.1.10"]);
+
+        assert!(!is_monitored(&any, &None));
+        assert!(is_monitored(&eth0, &None));
+        assert!(is_monitored(&any, &Some("any".to_string())));
+        assert!(!is_monitored(&eth0, &Some("any".to_string())));
+    }
+
+    #[test]
+    fn treats_addresses_of_all_devices_as_local_on_any() {
+        let devices = vec![device("any", &[]), device("eth0", &["192.168.1.10"]), device("wlan0", &["10.0.0.5", "fe80::1"])];
+        let extra: IpAddr = "172.17.0.1".parse().unwrap();
+
+        let any: Vec<String> = local_addresses(&devices[0], &devices, &[extra]).iter().map(IpAddr::to_string).collect();
+        assert_eq!(any, vec!["192.168.1.10", "10.0.0.5", "fe80::1", "172.17.0.1"]);
+
+        let wlan0: Vec<String> = local_addresses(&devices[2], &devices, &[]).iter().map(IpAddr::to_string).collect();
+        assert_eq!(wlan0, vec!["10.0.0.5", "fe80::1"]);
+    }
+
+    #[test]
+    fn replays_capture_file() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/capture/replay.pcap");
+        let config = Config { capture_file: Some(path.clone()), local_addresses: vec![IpAddr::V4(LOCAL)], ..Config::default() };
+        let flow_table = Mutex::new(FlowTable::new());
+        let health = CaptureHealth::default();
+
+        // DNS over UDP and one HTTPS connection, then an ARP request and a datagram between two other hosts
+        let packet_count = replay_file(open_file(&path, &None).unwrap(), &config, &flow_table, &health);
+        assert_eq!(packet_count, 7);
+        assert_eq!(health.packets_parsed.load(Ordering::Relaxed), 5);
+        assert_eq!(health.packets_ignored.load(Ordering::Relaxed), 2);
+        assert_eq!(health.parse_errors.load(Ordering::Relaxed), 0);
+
+        let flow_table = flow_table.into_inner().unwrap();
+        let mut flows: Vec<&Connection> = flow_table.iter().collect();
+        flows.sort_by_key(|flow| flow.destination.port());
+        assert_eq!(flows.len(), 2);
+
+        let dns = flows[0];
+        assert_eq!((dns.source, dns.destination), (SocketAddr::new(IpAddr::V4(LOCAL), 5353), SocketAddr::new(IpAddr::V4(REMOTE), 53)));
+        assert_eq!((dns.bytes_uploaded, dns.bytes_downloaded, dns.packets_uploaded, dns.packets_downloaded), (46, 62, 1, 1));
+
+        let https = flows[1];
+        assert_eq!(https.transport_type, TransportType::Tcp);
+        assert_eq!((https.inode, https.process_id), (0, 0));
+        assert_eq!((https.bytes_uploaded, https.bytes_downloaded, https.packets_uploaded, https.packets_downloaded), (208, 1054, 2, 1));
+        assert_eq!((https.payload_bytes_uploaded, https.payload_bytes_downloaded), (100, 1000));
+
+        let remotes = health.remotes.lock().unwrap();
+        assert_eq!(remotes.keys().collect::<Vec<&IpAddr>>(), vec![&IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)), &IpAddr::V4(REMOTE)]);
+        let cloudflare = remotes[&IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1))];
+        assert_eq!((cloudflare.bytes_uploaded, cloudflare.bytes_downloaded, cloudflare.packets_uploaded), (208, 1054, 2));
+    }
+
+    #[test]
+    fn fails_to_open_missing_capture_file() {
+        let error = open_file(Path::new("/nonexistent/capture.pcap"), &None).err().unwrap();
+
+        assert!(error.starts_with("Failed to open capture file /nonexistent/capture.pcap"));
+    }
+
+    #[test]
+    fn evicts_idle_flows_unless_their_socket_is_open() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let flow = |local_port: u16, idle: u64| Connection::new(
+            SocketAddr::new(IpAddr::V4(LOCAL), local_port),
+            SocketAddr::new(IpAddr::V4(REMOTE), 443),
+            TransportType::Tcp,
+            now - Duration::from_secs(idle),
+        );
+        let mut flow_table: FlowTable = vec![flow(1000, 10), flow(1001, 150), flow(1002, 900)].into_iter().collect();
+
+        let ports = |flow_table: &FlowTable| {
+            let mut ports: Vec<u16> = flow_table.iter().map(|flow| flow.source.port()).collect();
+            ports.sort_unstable();
+            ports
+        };
+
+        // Sampled every five minutes, a flow idle for a few minutes may not have been recorded yet
+        let sparse_history = Config { history_interval: 300, ..Config::default() };
+        evict_idle_flows(&mut flow_table, &[flow(1002, 0)], sparse_history.flow_idle_timeout(), now);
+        assert_eq!(ports(&flow_table), vec![1000, 1001, 1002]);
+
+        evict_idle_flows(&mut flow_table, &[flow(1002, 0)], Config::default().flow_idle_timeout(), now);
+        assert_eq!(ports(&flow_table), vec![1000, 1002]);
+    }
+
+    // Run with `cargo test --release bench_flow_table -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_flow_table_throughput() {
+        const FLOWS: u16 = 20_000;
+        const PACKETS: usize = 2_000_000;
+
+        let packets: Vec<Vec<u8>> = (0..FLOWS).map(|flow| {
+            let mut data = ipv4_udp();
+            data[20..22].copy_from_slice(&(1024 + flow % 60_000).to_be_bytes());
+            data[16] = (flow / 256) as u8;
+            data[17] = (flow % 256) as u8;
+            data
+        }).collect();
+
+        let mut flow_table = FlowTable::new();
+        let config = Config::default();
+        let started_at = Instant::now();
+
+        for index in 0..PACKETS {
+            let data = &packets[index * 7919 % packets.len()];
+            let (connection, size, direction) = process(data, Linktype::RAW, IpAddr::V4(LOCAL)).unwrap();
+            update_connections_with_bytes_transferred(&mut flow_table, connection, size, direction, &config);
+        }
+
+        let elapsed = started_at.elapsed();
+        println!(
+            "{} packets over {} flows in {:?} ({:.0} packets/sec)",
+            PACKETS, flow_table.len(), elapsed, PACKETS as f64 / elapsed.as_secs_f64()
+        );
+        assert_eq!(flow_table.len(), FLOWS as usize);
+    }
+
+    #[test]
+    fn accounts_payload_bytes_when_configured() {
+        let mut flow_table = FlowTable::new();
+        let config = Config { accounting: ByteAccounting::Payload, ..Config::default() };
+        let (connection, size, direction) = process(&ipv4_udp(), Linktype::RAW, IpAddr::V4(LOCAL)).unwrap();
+
+        update_connections_with_bytes_transferred(&mut flow_table, connection, size, direction, &config);
+
+        let flow = flow_table.iter().next().unwrap();
+        assert_eq!(flow.bytes_uploaded, 4);
+        assert_eq!(flow.wire_bytes_uploaded, 32);
+        assert_eq!(flow.payload_bytes_uploaded, 4);
+    }
 }
@@ -0,0 +1,31 @@
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::helpers::debug::is_debug;
+use crate::history::samples::SampleTracker;
+use crate::history::store::HistoryStore;
+use crate::structs::state::SharedState;
+
+pub fn run(interval: u64, state: SharedState, mut store: HistoryStore) -> JoinHandle<()> {
+    let mut tracker = SampleTracker::default();
+
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_millis(interval));
+
+        let (connection_samples, process_samples) = {
+            let mut state = state.lock().unwrap();
+            state.refresh();
+            tracker.sample(&state.connections, |connection| state.process_of(connection))
+        };
+
+        let sampled_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        if let Err(error) = store.record(sampled_at, &connection_samples, &process_samples) {
+            eprintln!("Error: Failed to write history: {}", error);
+        }
+
+        if is_debug() {
+            println!("Recorded {} connection samples", connection_samples.len());
+        }
+    })
+}
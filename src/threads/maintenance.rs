@@ -0,0 +1,22 @@
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::helpers::debug::is_debug;
+use crate::history::retention::RetentionPolicy;
+use crate::history::store::HistoryStore;
+
+pub fn run(interval: u64, mut store: HistoryStore, policy: RetentionPolicy) -> JoinHandle<()> {
+    thread::spawn(move || loop {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        if let Err(error) = store.maintain(now, &policy) {
+            eprintln!("Error: Failed to roll up history: {}", error);
+        }
+
+        if is_debug() {
+            println!("Rolled up history");
+        }
+
+        thread::sleep(Duration::from_millis(interval));
+    })
+}
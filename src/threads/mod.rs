@@ -1,3 +1,5 @@
 pub mod capture;
 pub mod connections;
+pub mod history;
+pub mod maintenance;
 pub mod processes;
@@ -1,56 +1,337 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fs;
 use std::thread;
 use std::thread::JoinHandle;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use libc::pid_t;
 use procfs::process::FDTarget::{Net, Other, Pipe, Socket};
+use procfs::process::{Process, Stat};
 
-use crate::structs::process::{ProcessInfo, ProcessInfos};
+use crate::helpers::users::user_name;
+use crate::structs::cgroup::Cgroup;
+use crate::structs::health::{ScanHealth, SharedScanHealth};
+use crate::structs::process::{InodeIndex, ProcessInfo, ProcessInfos, ProcessTable};
+use crate::structs::rate::Rates;
+use crate::structs::receivers::ProcessesReceiver;
 
-pub fn run(interval: u64) -> (JoinHandle<()>, single_value_channel::Receiver<Option<ProcessInfos>>) {
+// Scans wait at least this many times as long as the previous scan took, so they stay around 5% of a core
+const SCAN_COST_FACTOR: u32 = 20;
+const MAX_INTERVAL: Duration = Duration::from_secs(5);
+// Every fd table is re-read this often however long the interval, for changes the activity of a process misses
+const FULL_SCAN_INTERVAL: Duration = Duration::from_secs(2);
+
+pub fn run(interval: u64, health: SharedScanHealth) -> (JoinHandle<()>, ProcessesReceiver) {
     let (receiver, updater) = single_value_channel::channel();
+    let interval = Duration::from_millis(interval);
+
+    let handle = thread::spawn(move || {
+        let mut scanner = ProcessScanner::default();
+
+        loop {
+            let started_at = Instant::now();
+            let processes = scanner.scan(&health);
+            let inodes = index_inodes(&processes);
+            let elapsed = started_at.elapsed();
 
-    let handle = thread::spawn(move || loop {
-        updater.update(Some(get_inodes_per_process())).unwrap();
-        thread::sleep(Duration::from_millis(interval));
+            let next_interval = next_interval(interval, elapsed);
+            health.record_scan(elapsed, next_interval);
+
+            updater.update(Some(ProcessTable { processes, inodes })).unwrap();
+            thread::sleep(next_interval);
+        }
     });
 
     (handle, receiver)
 }
 
-pub fn get_inodes_per_process() -> ProcessInfos {
-    let mut process_infos = ProcessInfos::new();
-
-    for process_result in procfs::process::all_processes().unwrap() {
-        let process = match process_result {
-            Ok(process) => process,
-            Err(_) => continue,
-        };
-
-        let mut process_info = ProcessInfo {
-            pid: process.pid(),
-            command: process.cmdline().unwrap_or_default().join(" "),
-            executable: String::from(process.exe().unwrap_or_default().to_str().unwrap_or("")),
-            inodes: Vec::new()
-        };
-
-        if let Ok(file_descriptors) = process.fd() {
-            for file_descriptor_result in file_descriptors {
-                let file_descriptor = match file_descriptor_result {
-                    Ok(file_descriptor) => file_descriptor,
-                    Err(_) => continue,
-                };
-
-                match file_descriptor.target {
-                    Socket(inode) | Net(inode) | Pipe(inode) | Other(_, inode) => {
-                        process_info.inodes.push(inode)
-                    }
-                    _ => {}
+// Backs off when scanning gets expensive, never below the configured interval
+fn next_interval(interval: Duration, scan_duration: Duration) -> Duration {
+    (scan_duration * SCAN_COST_FACTOR).min(MAX_INTERVAL).max(interval)
+}
+
+// What moves when a process runs for a while. A process that wakes up to accept a connection and sleeps again within
+// one clock tick may change none of it, so this only decides what is read between full scans.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+struct Activity {
+    cpu_time: u64,
+    page_faults: u64,
+    threads: i64,
+    // Number of open fds on Linux 6.2 and later, 0 before
+    open_files: u64,
+}
+
+impl Activity {
+    fn of(process: &Process, stat: &Stat) -> Self {
+        Activity {
+            cpu_time: stat.utime + stat.stime,
+            page_faults: stat.minflt + stat.majflt,
+            threads: stat.num_threads,
+            open_files: fs::metadata(format!("/proc/{}/fd", process.pid())).map(|metadata| metadata.len()).unwrap_or(0),
+        }
+    }
+}
+
+struct CachedProcess {
+    info: ProcessInfo,
+    activity: Activity,
+}
+
+// Keeps what was read of each process between scans. The command and executable are only read for new
+// processes, or after an exec changed the process name, and fd tables, user ids and cgroups only when the process
+// ran since.
+#[derive(Default)]
+pub struct ProcessScanner {
+    cache: HashMap<pid_t, CachedProcess>,
+    // Resolved again on full scans, in case users were renamed or added
+    user_names: HashMap<u32, Option<String>>,
+    last_full_scan: Option<Instant>,
+}
+
+impl ProcessScanner {
+    pub fn scan(&mut self, health: &ScanHealth) -> ProcessInfos {
+        let full_scan = self.last_full_scan.is_none_or(|last_full_scan| last_full_scan.elapsed() >= FULL_SCAN_INTERVAL);
+        if full_scan {
+            self.last_full_scan = Some(Instant::now());
+            self.user_names.clear();
+        }
+
+        let mut process_infos = ProcessInfos::with_capacity(self.cache.len());
+        let mut cache = HashMap::with_capacity(self.cache.len());
+        let mut fd_tables_read = 0;
+
+        for process_result in procfs::process::all_processes().unwrap() {
+            let process = match process_result {
+                Ok(process) => process,
+                Err(_) => continue,
+            };
+
+            // Gone since it was listed
+            let stat = match process.stat() {
+                Ok(stat) => stat,
+                Err(_) => continue,
+            };
+            let activity = Activity::of(&process, &stat);
+
+            let cached = self.cache.remove(&process.pid())
+                .filter(|cached| cached.info.start_time == stat.starttime && cached.info.name == stat.comm);
+            let (mut process_info, stale) = match cached {
+                Some(cached) => (cached.info, full_scan || cached.activity != activity),
+                None => (ProcessInfo {
+                    pid: process.pid(),
+                    start_time: stat.starttime,
+                    exited: false,
+                    parent_pid: stat.ppid,
+                    name: stat.comm,
+                    command: process.cmdline().unwrap_or_default().join(" "),
+                    executable: String::from(process.exe().unwrap_or_default().to_str().unwrap_or("")),
+                    uid: 0,
+                    effective_uid: 0,
+                    user: None,
+                    cgroup: None,
+                    inodes: Vec::new(),
+                    rates: Rates::new(),
+                }, true),
+            };
+
+            // Changes when the parent exits and the process is reparented
+            process_info.parent_pid = stat.ppid;
+
+            if stale {
+                if let Ok(status) = process.status() {
+                    process_info.uid = status.ruid;
+                    process_info.effective_uid = status.euid;
+                    process_info.user = self.user_names.entry(status.ruid).or_insert_with(|| user_name(status.ruid)).clone();
                 }
+                process_info.cgroup = Cgroup::read(format!("/proc/{}/cgroup", process_info.pid)).ok();
+                process_info.inodes = read_inodes(&process);
+                fd_tables_read += 1;
             }
+
+            process_infos.insert(process_info.pid, process_info.clone());
+            cache.insert(process_info.pid, CachedProcess { info: process_info, activity });
         }
 
-        process_infos.insert(process_info.pid, process_info);
+        self.cache = cache;
+        health.record_processes(process_infos.len(), fd_tables_read);
+
+        process_infos
     }
+}
+
+fn read_inodes(process: &Process) -> Vec<u64> {
+    let mut inodes = Vec::new();
 
-    process_infos
+    if let Ok(file_descriptors) = process.fd() {
+        for file_descriptor_result in file_descriptors {
+            let file_descriptor = match file_descriptor_result {
+                Ok(file_descriptor) => file_descriptor,
+                Err(_) => continue,
+            };
+
+            match file_descriptor.target {
+                Socket(inode) | Net(inode) | Pipe(inode) | Other(_, inode) => inodes.push(inode),
+                _ => {}
+            }
+        }
+    }
+
+    inodes
+}
+
+// A socket shared between processes, e.g. after a fork, goes to the lowest pid, usually the parent
+pub fn index_inodes(processes: &ProcessInfos) -> InodeIndex {
+    let mut inodes = InodeIndex::with_capacity(processes.values().map(|process| process.inodes.len()).sum());
+
+    for process in processes.values() {
+        for inode in &process.inodes {
+            match inodes.entry(*inode) {
+                Entry::Occupied(mut entry) => if process.pid < entry.get().pid {
+                    entry.insert(process.key());
+                },
+                Entry::Vacant(entry) => {
+                    entry.insert(process.key());
+                }
+            }
+        }
+    }
+
+    inodes
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, UdpSocket};
+    use std::os::unix::fs::MetadataExt;
+    use std::sync::atomic::Ordering;
+    use std::time::{Duration, Instant};
+
+    use libc::pid_t;
+
+    use super::{index_inodes, next_interval, ProcessScanner, FULL_SCAN_INTERVAL};
+    use crate::helpers::testing::{self, by_pid};
+    use crate::helpers::users::user_name;
+    use crate::structs::health::ScanHealth;
+    use crate::structs::connection::Connection;
+    use crate::structs::process::{ProcessInfo, ProcessInfos, ProcessKey};
+
+    fn process(pid: pid_t, inodes: Vec<u64>) -> ProcessInfo {
+        ProcessInfo { start_time: pid as u64 * 10, inodes, ..testing::process(pid, "/usr/bin/test") }
+    }
+
+    // Spreads connections over local addresses so that a whole table of them stays distinct
+    fn connection(index: u32, inode: u64) -> Connection {
+        Connection {
+            source: SocketAddr::from(([10, 0, (index >> 16) as u8, (index >> 8) as u8], 1024 + (index & 0xff) as u16)),
+            inode,
+            ..testing::connection(0)
+        }
+    }
+
+    #[test]
+    fn indexes_shared_sockets_under_the_lowest_pid() {
+        let processes = by_pid(vec![process(300, vec![1, 2]), process(200, vec![2, 3]), process(400, vec![3])]);
+
+        let inodes = index_inodes(&processes);
+
+        assert_eq!(inodes.len(), 3);
+        assert_eq!(inodes[&1].pid, 300);
+        assert_eq!(inodes[&2].pid, 200);
+        assert_eq!(inodes[&3], ProcessKey { pid: 200, start_time: 2000 });
+
+        let mut unknown = connection(0, 99);
+        assert!(!unknown.bind_matching_process(&inodes));
+        assert_eq!(unknown.process_id, 0);
+
+        let mut shared = connection(1, 3);
+        assert!(shared.bind_matching_process(&inodes));
+        assert_eq!((shared.process_id, shared.process_start_time), (200, 2000));
+    }
+
+    fn socket_inode(socket: &UdpSocket) -> u64 {
+        let path = format!("/proc/self/fd/{}", std::os::unix::io::AsRawFd::as_raw_fd(socket));
+        std::fs::metadata(path).unwrap().ino()
+    }
+
+    #[test]
+    fn rescans_only_processes_that_ran() {
+        let health = ScanHealth::default();
+        let mut scanner = ProcessScanner::default();
+        let pid = std::process::id() as pid_t;
+
+        let first = scanner.scan(&health);
+        let first_reads = health.fd_tables_read.load(Ordering::Relaxed);
+        assert_eq!(first_reads as usize, first.len());
+        assert_eq!(health.processes.load(Ordering::Relaxed), first.len());
+
+        // This process keeps running, so the socket it opens shows up on the next scan
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let second = scanner.scan(&health);
+        let second_reads = health.fd_tables_read.load(Ordering::Relaxed) - first_reads;
+
+        assert!(second[&pid].inodes.contains(&socket_inode(&socket)));
+        assert_eq!(second[&pid].start_time, first[&pid].start_time);
+        assert_eq!(second[&pid].executable, first[&pid].executable);
+        assert_eq!(second[&pid].parent_pid, unsafe { libc::getppid() });
+        assert_eq!(second[&pid].uid, unsafe { libc::getuid() });
+        assert_eq!(second[&pid].user, user_name(second[&pid].uid));
+        assert!(second[&pid].executable.rsplit('/').next().unwrap().starts_with(&second[&pid].name));
+        assert!((second_reads as usize) < second.len(), "{} of {} fd tables read again", second_reads, second.len());
+    }
+
+    #[test]
+    fn rereads_every_fd_table_on_full_scans() {
+        let health = ScanHealth::default();
+        let mut scanner = ProcessScanner::default();
+        scanner.scan(&health);
+
+        scanner.last_full_scan = Some(Instant::now() - FULL_SCAN_INTERVAL);
+        let reads_before = health.fd_tables_read.load(Ordering::Relaxed);
+        let processes = scanner.scan(&health);
+
+        assert_eq!((health.fd_tables_read.load(Ordering::Relaxed) - reads_before) as usize, processes.len());
+    }
+
+    #[test]
+    fn backs_off_when_scans_get_expensive() {
+        let interval = Duration::from_millis(200);
+
+        assert_eq!(next_interval(interval, Duration::from_millis(2)), interval);
+        assert_eq!(next_interval(interval, Duration::from_millis(50)), Duration::from_secs(1));
+        assert_eq!(next_interval(interval, Duration::from_secs(2)), Duration::from_secs(5));
+    }
+
+    // Run with `cargo test --release bench_process_binding -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_process_binding() {
+        const SOCKETS: u32 = 50_000;
+        const PROCESSES: u32 = 2_000;
+        const FDS_PER_PROCESS: u32 = 40;
+
+        // Every process holds its share of the sockets plus pipes and other fds that never match
+        let processes: ProcessInfos = (0..PROCESSES).map(|pid| {
+            let sockets = (pid..SOCKETS).step_by(PROCESSES as usize).map(u64::from);
+            let others = (0..FDS_PER_PROCESS).map(|fd| u64::from(SOCKETS + pid * FDS_PER_PROCESS + fd));
+            (pid as pid_t, process(pid as pid_t, sockets.chain(others).collect()))
+        }).collect();
+        let mut connections: Vec<Connection> = (0..SOCKETS).map(|index| connection(index, u64::from(index))).collect();
+
+        let started_at = Instant::now();
+        let inodes = index_inodes(&processes);
+        let indexed_in = started_at.elapsed();
+
+        let started_at = Instant::now();
+        for connection in connections.iter_mut() {
+            connection.bind_matching_process(&inodes);
+        }
+        let bound_in = started_at.elapsed();
+
+        println!(
+            "{} sockets over {} processes: index built in {:?}, bound in {:?}",
+            SOCKETS, PROCESSES, indexed_in, bound_in
+        );
+        assert!(connections.iter().all(|connection| connection.process_id as u64 == connection.inode % u64::from(PROCESSES)));
+    }
 }